@@ -3,7 +3,7 @@ mkdir -p $XDG_CONFIG_HOME/sampic
 CONFIG_LOCATION="$XDG_CONFIG_HOME/sampic/sampic.toml"
 
 cat >$CONFIG_LOCATION <<EOF
-storage = 'Local'
+storage = 's3'
 api_key = '$API_KEY'
 api_secret_key = '$SECRET_KEY'
 region = 'fr-par'
@@ -0,0 +1,581 @@
+//! Annotations drawn over a screenshot before it's saved: arrows, boxes,
+//! freehand lines, text, numbered steps, a highlighter and boxes that
+//! pixelate or blur what's under them.
+//!
+//! `flatten` draws annotations into an image. `annotate` opens an editor
+//! window over a screenshot and flattens whatever was drawn in it.
+use crate::font;
+use crate::piston::Window;
+use crate::region::{self, GuiError};
+use glutin_window::GlutinWindow;
+use graphics::draw_state::DrawState;
+use graphics::{clear, rectangle, Image};
+use image::{Pixel, Rgba, RgbaImage};
+use imageproc::drawing::{draw_filled_circle_mut, draw_filled_rect_mut, draw_polygon_mut};
+use imageproc::point::Point;
+use imageproc::rect::Rect;
+use opengl_graphics::{GlGraphics, OpenGL, Texture, TextureSettings};
+use piston::event_loop::{EventSettings, Events};
+use piston::input::*;
+use piston::window::WindowSettings;
+
+/// Colors picked with the 1 to 5 keys.
+pub const PALETTE: [[u8; 4]; 5] = [
+    [230, 30, 30, 255],
+    [255, 210, 0, 255],
+    [30, 180, 60, 255],
+    [30, 100, 230, 255],
+    [20, 20, 20, 255],
+];
+/// Radius of lines, arrows and box outlines.
+const STROKE: i32 = 2;
+const ARROW_HEAD: f64 = 16.0;
+const TEXT_SCALE: u32 = 3;
+const STEP_RADIUS: i32 = 14;
+const HIGHLIGHTER_ALPHA: u8 = 110;
+const PIXEL_SIZE: u32 = 10;
+const BLUR_SIGMA: f32 = 8.0;
+/// Height of the status line along the bottom of the editor.
+const STATUS_HEIGHT: u32 = 24;
+/// Where the status text starts, right of the color swatch.
+const STATUS_LEFT: f64 = 28.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tool {
+    Arrow,
+    Rectangle,
+    Pen,
+    Text,
+    Step,
+    Highlighter,
+    Pixelate,
+    Blur,
+}
+
+impl Tool {
+    const ALL: [Tool; 8] = [
+        Tool::Arrow,
+        Tool::Rectangle,
+        Tool::Pen,
+        Tool::Text,
+        Tool::Step,
+        Tool::Highlighter,
+        Tool::Pixelate,
+        Tool::Blur,
+    ];
+
+    /// Tool picked with a key in the editor.
+    fn from_key(key: char) -> Option<Tool> {
+        match key {
+            'a' => Some(Tool::Arrow),
+            'r' => Some(Tool::Rectangle),
+            'p' => Some(Tool::Pen),
+            't' => Some(Tool::Text),
+            'n' => Some(Tool::Step),
+            'h' => Some(Tool::Highlighter),
+            'x' => Some(Tool::Pixelate),
+            'b' => Some(Tool::Blur),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Tool::Arrow => "(a)rrow",
+            Tool::Rectangle => "(r)ectangle",
+            Tool::Pen => "(p)en",
+            Tool::Text => "(t)ext",
+            Tool::Step => "(n)umbered step",
+            Tool::Highlighter => "(h)ighlighter",
+            Tool::Pixelate => "pi(x)elate",
+            Tool::Blur => "(b)lur",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Shape {
+    Arrow([f64; 2], [f64; 2]),
+    /// `[x, y, w, h]`, as are the other boxes.
+    Rectangle([f64; 4]),
+    Pen(Vec<[f64; 2]>),
+    /// Top left corner and text.
+    Text([f64; 2], String),
+    /// Center and number.
+    Step([f64; 2], u32),
+    Highlighter([f64; 4]),
+    Pixelate([f64; 4]),
+    Blur([f64; 4]),
+    /// Box filled with the annotation's color.
+    Blackout([f64; 4]),
+}
+
+#[derive(Clone, Debug)]
+pub struct Annotation {
+    pub shape: Shape,
+    pub color: Rgba<u8>,
+}
+
+fn stamp(image: &mut RgbaImage, at: [f64; 2], color: Rgba<u8>) {
+    draw_filled_circle_mut(image, (at[0] as i32, at[1] as i32), STROKE, color);
+}
+
+/// Thick line, drawn as a dot every pixel along it.
+fn stroke(image: &mut RgbaImage, from: [f64; 2], to: [f64; 2], color: Rgba<u8>) {
+    let steps = (to[0] - from[0])
+        .abs()
+        .max((to[1] - from[1]).abs())
+        .ceil()
+        .max(1.0) as u32;
+    for step in 0..=steps {
+        let t = f64::from(step) / f64::from(steps);
+        let at = [
+            from[0] + (to[0] - from[0]) * t,
+            from[1] + (to[1] - from[1]) * t,
+        ];
+        stamp(image, at, color);
+    }
+}
+
+fn arrow(image: &mut RgbaImage, from: [f64; 2], to: [f64; 2], color: Rgba<u8>) {
+    stroke(image, from, to, color);
+    if from == to {
+        return;
+    }
+    let angle = (to[1] - from[1]).atan2(to[0] - from[0]);
+    let corner = |spread: f64| {
+        Point::new(
+            (to[0] - ARROW_HEAD * (angle + spread).cos()) as i32,
+            (to[1] - ARROW_HEAD * (angle + spread).sin()) as i32,
+        )
+    };
+    let head = [
+        Point::new(to[0] as i32, to[1] as i32),
+        corner(0.5),
+        corner(-0.5),
+    ];
+    draw_polygon_mut(image, &head, color);
+}
+
+/// Part of a box inside the image, in whole pixels.
+fn bounds(image: &RgbaImage, rect: [f64; 4]) -> Option<(u32, u32, u32, u32)> {
+    let x = rect[0].max(0.0) as u32;
+    let y = rect[1].max(0.0) as u32;
+    let right = ((rect[0] + rect[2]).max(0.0) as u32).min(image.width());
+    let bottom = ((rect[1] + rect[3]).max(0.0) as u32).min(image.height());
+    if right <= x || bottom <= y {
+        return None;
+    }
+    Some((x, y, right - x, bottom - y))
+}
+
+fn outline(image: &mut RgbaImage, rect: [f64; 4], color: Rgba<u8>) {
+    let [x, y, w, h] = rect;
+    stroke(image, [x, y], [x + w, y], color);
+    stroke(image, [x + w, y], [x + w, y + h], color);
+    stroke(image, [x + w, y + h], [x, y + h], color);
+    stroke(image, [x, y + h], [x, y], color);
+}
+
+fn highlight(image: &mut RgbaImage, rect: [f64; 4], color: Rgba<u8>) {
+    let (x, y, w, h) = match bounds(image, rect) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let tint = Rgba([color[0], color[1], color[2], HIGHLIGHTER_ALPHA]);
+    for py in y..y + h {
+        for px in x..x + w {
+            image.get_pixel_mut(px, py).blend(&tint);
+        }
+    }
+}
+
+/// Replaces every `PIXEL_SIZE` block of the box with its average color.
+fn pixelate(image: &mut RgbaImage, rect: [f64; 4]) {
+    let (x, y, w, h) = match bounds(image, rect) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    for block_y in (y..y + h).step_by(PIXEL_SIZE as usize) {
+        for block_x in (x..x + w).step_by(PIXEL_SIZE as usize) {
+            let right = (block_x + PIXEL_SIZE).min(x + w);
+            let bottom = (block_y + PIXEL_SIZE).min(y + h);
+            let mut sum = [0u64; 4];
+            for py in block_y..bottom {
+                for px in block_x..right {
+                    for (total, channel) in sum.iter_mut().zip(image.get_pixel(px, py).0.iter()) {
+                        *total += u64::from(*channel);
+                    }
+                }
+            }
+            let count = u64::from((right - block_x) * (bottom - block_y));
+            let average = Rgba([
+                (sum[0] / count) as u8,
+                (sum[1] / count) as u8,
+                (sum[2] / count) as u8,
+                (sum[3] / count) as u8,
+            ]);
+            for py in block_y..bottom {
+                for px in block_x..right {
+                    image.put_pixel(px, py, average);
+                }
+            }
+        }
+    }
+}
+
+fn blur(image: &mut RgbaImage, rect: [f64; 4]) {
+    let (x, y, w, h) = match bounds(image, rect) {
+        Some(bounds) => bounds,
+        None => return,
+    };
+    let area = image::imageops::crop_imm(&*image, x, y, w, h).to_image();
+    let blurred = imageproc::filter::gaussian_blur_f32(&area, BLUR_SIGMA);
+    image::imageops::replace(image, &blurred, x, y);
+}
+
+fn text(image: &mut RgbaImage, at: [f64; 2], label: &str, scale: u32, color: Rgba<u8>) {
+    for (x, y) in font::pixels(label) {
+        let rect = Rect::at(
+            at[0] as i32 + (x * scale) as i32,
+            at[1] as i32 + (y * scale) as i32,
+        )
+        .of_size(scale, scale);
+        draw_filled_rect_mut(image, rect, color);
+    }
+}
+
+fn step(image: &mut RgbaImage, center: [f64; 2], number: u32, color: Rgba<u8>) {
+    draw_filled_circle_mut(
+        image,
+        (center[0] as i32, center[1] as i32),
+        STEP_RADIUS,
+        color,
+    );
+    let label = number.to_string();
+    let scale = 2;
+    let w = f64::from(font::width(&label) * scale);
+    let h = f64::from(font::HEIGHT * scale);
+    let corner = [center[0] - w / 2.0, center[1] - h / 2.0];
+    text(image, corner, &label, scale, Rgba([255, 255, 255, 255]));
+}
+
+/// Draws `annotations` into `image`, in order.
+pub fn flatten(image: &mut RgbaImage, annotations: &[Annotation]) {
+    for annotation in annotations {
+        let color = annotation.color;
+        match &annotation.shape {
+            Shape::Arrow(from, to) => arrow(image, *from, *to, color),
+            Shape::Rectangle(rect) => outline(image, *rect, color),
+            Shape::Pen(points) => {
+                for pair in points.windows(2) {
+                    stroke(image, pair[0], pair[1], color);
+                }
+                if let [point] = points.as_slice() {
+                    stamp(image, *point, color);
+                }
+            }
+            Shape::Text(at, label) => text(image, *at, label, TEXT_SCALE, color),
+            Shape::Step(center, number) => step(image, *center, *number, color),
+            Shape::Highlighter(rect) => highlight(image, *rect, color),
+            Shape::Pixelate(rect) => pixelate(image, *rect),
+            Shape::Blur(rect) => blur(image, *rect),
+            Shape::Blackout(rect) => {
+                if let Some((x, y, w, h)) = bounds(image, *rect) {
+                    draw_filled_rect_mut(image, Rect::at(x as i32, y as i32).of_size(w, h), color);
+                }
+            }
+        }
+    }
+}
+
+/// Shape `tool` draws when dragged from `anchor` to `cursor`.
+fn dragged(tool: Tool, anchor: [f64; 2], cursor: [f64; 2], points: &[[f64; 2]]) -> Option<Shape> {
+    let rect = region::normalized(anchor, cursor);
+    match tool {
+        Tool::Arrow => Some(Shape::Arrow(anchor, cursor)),
+        Tool::Rectangle => Some(Shape::Rectangle(rect)),
+        Tool::Pen => Some(Shape::Pen(points.to_vec())),
+        Tool::Highlighter => Some(Shape::Highlighter(rect)),
+        Tool::Pixelate => Some(Shape::Pixelate(rect)),
+        Tool::Blur => Some(Shape::Blur(rect)),
+        Tool::Text | Tool::Step => None,
+    }
+}
+
+fn status(tool: Tool) -> String {
+    format!("{}  1-5 color  ctrl+z undo  enter done", tool.name())
+}
+
+/// Smallest editor window the status line fits in, whichever tool is picked.
+fn min_size() -> [u32; 2] {
+    let widest = Tool::ALL
+        .iter()
+        .map(|&tool| region::text_width(&status(tool)))
+        .fold(0.0, f64::max);
+    [(STATUS_LEFT + widest).ceil() as u32, STATUS_HEIGHT]
+}
+
+/// Opens an editor over the `w`x`h` RGBA `buffer` and returns the buffer
+/// with the annotations drawn in, or `None` if the editor was closed with Esc.
+/// Fails if the editor's window can't be opened.
+///
+/// Letters pick the tool, 1 to 5 the color, Ctrl+Z undoes and Enter is done.
+/// With the text tool, click where the text goes, type it and press Enter.
+pub fn annotate(buffer: Vec<u8>, w: u32, h: u32) -> Result<Option<Vec<u8>>, GuiError> {
+    let base = RgbaImage::from_raw(w, h, buffer).ok_or_else(|| {
+        GuiError::TextureError(format!("the buffer doesn't hold a {}x{} image", w, h))
+    })?;
+    // Change this to OpenGL::V2_1 if not working.
+    let opengl = OpenGL::V3_2;
+    // Small screenshots get a larger window so the status line fits, the
+    // rest of it is left black.
+    let [min_w, min_h] = min_size();
+    let size = [w.max(min_w), h.max(min_h)];
+    let mut window: GlutinWindow = WindowSettings::new("sampic", size)
+        .resizable(false)
+        .graphics_api(opengl)
+        .exit_on_esc(true)
+        .samples(2)
+        .build()
+        .map_err(|e| GuiError::WindowError(e.to_string()))?;
+    let mut gl = GlGraphics::new(opengl);
+    let texture_settings = TextureSettings::new();
+    let mut texture = Texture::from_image(&base, &texture_settings);
+    let draw_state = DrawState::new_alpha();
+
+    let mut events = Events::new(EventSettings::new());
+    let mut annotations: Vec<Annotation> = Vec::new();
+    let mut tool = Tool::Arrow;
+    let mut color = PALETTE[0];
+    let mut cursor = [0.0, 0.0];
+    // Start of the drag in progress and the points it went through.
+    let mut drag: Option<([f64; 2], Vec<[f64; 2]>)> = None;
+    let mut typing = false;
+    let mut ctrl = false;
+    let mut dirty = false;
+    // The annotations flattened over `base`, until one of them changes. Only
+    // the drag in progress is drawn again on every mouse move.
+    let mut committed: Option<RgbaImage> = None;
+    let mut confirmed = false;
+
+    while let Some(e) = events.next(&mut window) {
+        e.mouse_cursor(|pos| {
+            cursor = pos;
+            if let Some((_, points)) = &mut drag {
+                points.push(pos);
+                dirty = true;
+            }
+        });
+        e.text(|typed| {
+            let typed: String = typed.chars().filter(|c| !c.is_control()).collect();
+            if typing {
+                if let Some(Annotation {
+                    shape: Shape::Text(_, label),
+                    ..
+                }) = annotations.last_mut()
+                {
+                    label.push_str(&typed);
+                    committed = None;
+                    dirty = true;
+                }
+            } else if !ctrl {
+                for key in typed.chars() {
+                    if let Some(picked) = Tool::from_key(key) {
+                        tool = picked;
+                    }
+                    if let Some(index) = key.to_digit(10) {
+                        if index >= 1 && index as usize <= PALETTE.len() {
+                            color = PALETTE[index as usize - 1];
+                        }
+                    }
+                }
+            }
+        });
+        match e.press_args() {
+            Some(Button::Mouse(MouseButton::Left)) => {
+                typing = false;
+                let color = Rgba(color);
+                match tool {
+                    Tool::Text => {
+                        annotations.push(Annotation {
+                            shape: Shape::Text(cursor, String::new()),
+                            color,
+                        });
+                        typing = true;
+                    }
+                    Tool::Step => {
+                        let number = annotations
+                            .iter()
+                            .filter(|annotation| matches!(annotation.shape, Shape::Step(..)))
+                            .count() as u32
+                            + 1;
+                        annotations.push(Annotation {
+                            shape: Shape::Step(cursor, number),
+                            color,
+                        });
+                    }
+                    _ => drag = Some((cursor, vec![cursor])),
+                }
+                if drag.is_none() {
+                    committed = None;
+                }
+                dirty = true;
+            }
+            Some(Button::Keyboard(Key::LCtrl)) | Some(Button::Keyboard(Key::RCtrl)) => {
+                ctrl = true;
+            }
+            Some(Button::Keyboard(Key::Z)) if ctrl => {
+                annotations.pop();
+                typing = false;
+                committed = None;
+                dirty = true;
+            }
+            Some(Button::Keyboard(Key::Backspace)) if typing => {
+                if let Some(Annotation {
+                    shape: Shape::Text(_, label),
+                    ..
+                }) = annotations.last_mut()
+                {
+                    label.pop();
+                    committed = None;
+                    dirty = true;
+                }
+            }
+            Some(Button::Keyboard(Key::Return)) | Some(Button::Keyboard(Key::NumPadEnter)) => {
+                if typing {
+                    typing = false;
+                } else {
+                    confirmed = true;
+                    window.set_should_close(true);
+                }
+            }
+            _ => {}
+        };
+        match e.release_args() {
+            Some(Button::Mouse(MouseButton::Left)) => {
+                if let Some((anchor, points)) = drag.take() {
+                    if let Some(shape) = dragged(tool, anchor, cursor, &points) {
+                        annotations.push(Annotation {
+                            shape,
+                            color: Rgba(color),
+                        });
+                    }
+                    committed = None;
+                    dirty = true;
+                }
+            }
+            Some(Button::Keyboard(Key::LCtrl)) | Some(Button::Keyboard(Key::RCtrl)) => {
+                ctrl = false;
+            }
+            _ => {}
+        };
+        if dirty {
+            let mut preview = committed
+                .get_or_insert_with(|| {
+                    let mut layer = base.clone();
+                    flatten(&mut layer, &annotations);
+                    layer
+                })
+                .clone();
+            // Redaction boxes are slow to preview on every mouse move, so
+            // only their outline is shown until the drag ends.
+            if let Some((anchor, points)) = &drag {
+                let shape = match dragged(tool, *anchor, cursor, points) {
+                    Some(Shape::Pixelate(rect)) | Some(Shape::Blur(rect)) => {
+                        Some(Shape::Rectangle(rect))
+                    }
+                    shape => shape,
+                };
+                if let Some(shape) = shape {
+                    let current = Annotation {
+                        shape,
+                        color: Rgba(color),
+                    };
+                    flatten(&mut preview, &[current]);
+                }
+            }
+            texture = Texture::from_image(&preview, &texture_settings);
+            dirty = false;
+        }
+        if let Some(args) = e.render_args() {
+            let status = status(tool);
+            let bottom = f64::from(size[1] - STATUS_HEIGHT);
+            gl.draw(args.viewport(), |c, gl| {
+                clear([0.0, 0.0, 0.0, 1.0], gl);
+                Image::new().draw(&texture, &draw_state, c.transform, gl);
+                let [r, g, b, _] = color;
+                rectangle(
+                    [
+                        f32::from(r) / 255.0,
+                        f32::from(g) / 255.0,
+                        f32::from(b) / 255.0,
+                        1.0,
+                    ],
+                    [
+                        0.0,
+                        bottom,
+                        f64::from(STATUS_HEIGHT),
+                        f64::from(STATUS_HEIGHT),
+                    ],
+                    c.transform,
+                    gl,
+                );
+                region::text(&status, [STATUS_LEFT, bottom], c.transform, gl);
+            });
+        }
+    }
+    if !confirmed {
+        return Ok(None);
+    }
+    let mut result = base;
+    flatten(&mut result, &annotations);
+    Ok(Some(result.into_raw()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> RgbaImage {
+        RgbaImage::from_fn(20, 20, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgba([0, 0, 0, 255])
+            } else {
+                Rgba([200, 200, 200, 255])
+            }
+        })
+    }
+
+    #[test]
+    fn pixelated_blocks_are_uniform() {
+        let mut image = checkerboard();
+        let annotation = Annotation {
+            shape: Shape::Pixelate([0.0, 0.0, 10.0, 10.0]),
+            color: Rgba(PALETTE[0]),
+        };
+        flatten(&mut image, &[annotation]);
+        let first = *image.get_pixel(0, 0);
+        assert!((0..10).all(|y| (0..10).all(|x| *image.get_pixel(x, y) == first)));
+        assert_eq!(first, Rgba([100, 100, 100, 255]));
+        // Outside the box is left alone.
+        assert_eq!(*image.get_pixel(10, 10), Rgba([0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn boxes_outside_the_image_are_ignored() {
+        let mut image = checkerboard();
+        let annotations = [
+            Annotation {
+                shape: Shape::Blur([30.0, 30.0, 10.0, 10.0]),
+                color: Rgba(PALETTE[0]),
+            },
+            Annotation {
+                shape: Shape::Highlighter([-20.0, -20.0, 5.0, 5.0]),
+                color: Rgba(PALETTE[1]),
+            },
+        ];
+        flatten(&mut image, &annotations);
+        assert_eq!(image, checkerboard());
+    }
+}
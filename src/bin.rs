@@ -1,5 +1,4 @@
 #![feature(proc_macro_hygiene, decl_macro)]
-#[macro_use]
 extern crate rocket;
 #[macro_use]
 extern crate serde_derive;
@@ -10,25 +9,418 @@ extern crate graphics;
 extern crate opengl_graphics;
 extern crate piston;
 
+use lib::error::{Class, Context, Error};
 use sampicore as lib;
+
+/// Error for an unusable command line argument.
+fn invalid(message: &str) -> Error {
+    Error::new(Class::Input, message)
+}
+
+/// Adds the options choosing what part of which display is captured.
+fn region_args(command: clap::App<'static, 'static>) -> clap::App<'static, 'static> {
+    use clap::Arg;
+    const REGIONS: [&str; 4] = ["REGION", "FULLSCREEN", "LAST_REGION", "WINDOW"];
+    let others = |name: &str| -> Vec<&'static str> {
+        REGIONS
+            .iter()
+            .copied()
+            .filter(|other| *other != name)
+            .collect()
+    };
+    command
+        .arg(
+            Arg::with_name("DISPLAY")
+                .short("d")
+                .long("display")
+                .takes_value(true)
+                .help("Index of the display to capture, 0 is the first one, or its name, e.g. HDMI-1."),
+        )
+        .arg(
+            Arg::with_name("REGION")
+                .short("r")
+                .long("region")
+                .takes_value(true)
+                .conflicts_with_all(&others("REGION"))
+                .help("Capture x,y,w,h without opening the region selector."),
+        )
+        .arg(
+            Arg::with_name("FULLSCREEN")
+                .long("fullscreen")
+                .conflicts_with_all(&others("FULLSCREEN"))
+                .help("Capture the whole screen without opening the region selector."),
+        )
+        .arg(
+            Arg::with_name("LAST_REGION")
+                .long("last-region")
+                .conflicts_with_all(&others("LAST_REGION"))
+                .help("Capture the same region as the previous screenshot."),
+        )
+        .arg(
+            Arg::with_name("WINDOW")
+                .short("w")
+                .long("window")
+                .takes_value(true)
+                .conflicts_with_all(&others("WINDOW"))
+                .help("Capture the X11 window with this id, or whose title contains this."),
+        )
+}
+
+/// Subcommand taking a screenshot, with the options every backend shares.
+fn screenshot_command(name: &'static str, about: &'static str) -> clap::App<'static, 'static> {
+    use clap::Arg;
+    region_args(clap::SubCommand::with_name(name))
+        .about(about)
+        .arg(
+            Arg::with_name("FORMAT")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&lib::img::FORMATS)
+                .help("Output format, defaults to the configured one."),
+        )
+        .arg(
+            Arg::with_name("QUALITY")
+                .short("q")
+                .long("quality")
+                .takes_value(true)
+                .help("JPEG, AVIF and lossy WebP quality, from 1 to 100."),
+        )
+        .arg(
+            Arg::with_name("LOSSLESS")
+                .long("lossless")
+                .conflicts_with("NO_LOSSLESS")
+                .help("Encode WebP losslessly."),
+        )
+        .arg(
+            Arg::with_name("NO_LOSSLESS")
+                .long("no-lossless")
+                .help("Encode WebP lossily, even if webp_lossless is set in the config."),
+        )
+        .arg(
+            Arg::with_name("COMPRESSION")
+                .long("compression")
+                .takes_value(true)
+                .possible_values(&lib::img::PNG_COMPRESSION)
+                .help("PNG compression level."),
+        )
+        .arg(
+            Arg::with_name("OPTIMIZE")
+                .long("optimize")
+                .conflicts_with("NO_OPTIMIZE")
+                .help("Run PNGs through a lossless optimization pass."),
+        )
+        .arg(
+            Arg::with_name("NO_OPTIMIZE")
+                .long("no-optimize")
+                .help("Skip the optimization pass, even if optimize_png is set in the config."),
+        )
+        .arg(
+            Arg::with_name("ALL_DISPLAYS")
+                .short("a")
+                .long("all-displays")
+                .conflicts_with("DISPLAY")
+                .help("Capture every display side by side."),
+        )
+        .arg(
+            Arg::with_name("ANNOTATE")
+                .short("e")
+                .long("annotate")
+                // A series runs unattended.
+                .conflicts_with("COUNT")
+                .help("Draw arrows, boxes, text or redactions on the screenshot before saving it."),
+        )
+        .arg(
+            Arg::with_name("REDACT")
+                .long("redact")
+                .conflicts_with("NO_REDACT")
+                .help("Cover emails, keys and tokens found in the screenshot before saving it."),
+        )
+        .arg(
+            Arg::with_name("NO_REDACT")
+                .long("no-redact")
+                .help("Don't redact, even if redaction is enabled in the config."),
+        )
+        .arg(
+            Arg::with_name("DELAY")
+                .long("delay")
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Wait this many seconds before taking the screenshot."),
+        )
+        .arg(
+            Arg::with_name("COUNTDOWN")
+                .long("countdown")
+                .requires("DELAY")
+                .help("Show a notification counting down the delay."),
+        )
+        .arg(
+            Arg::with_name("INTERVAL")
+                .long("interval")
+                .takes_value(true)
+                .value_name("SECS")
+                .requires("COUNT")
+                .help("Seconds between the screenshots of a series."),
+        )
+        .arg(
+            Arg::with_name("COUNT")
+                .long("count")
+                .takes_value(true)
+                .requires("INTERVAL")
+                .help("Number of screenshots to take, one every --interval seconds."),
+        )
+}
+
+/// Seconds given as a whole or decimal number.
+fn seconds(matches: &clap::ArgMatches, name: &str) -> lib::error::Result<std::time::Duration> {
+    let seconds: f64 = matches
+        .value_of(name)
+        .unwrap()
+        .parse()
+        .map_err(|_| invalid("Seconds must be a number."))?;
+    // `Duration` can't hold infinities or anything past u64::MAX seconds.
+    if !seconds.is_finite() || seconds >= u64::MAX as f64 {
+        return Err(invalid("Seconds must be a finite number."));
+    }
+    Ok(std::time::Duration::from_secs_f64(seconds.max(0.0)))
+}
+
+/// The configured encoding, overridden by any flags given on the command line.
+fn encoding(matches: Option<&clap::ArgMatches>) -> lib::error::Result<lib::img::Encoding> {
+    let cfg = lib::config::config()?;
+    let mut encoding = lib::img::Encoding::from_config(&cfg);
+    let matches = match matches {
+        Some(matches) => matches,
+        None => return Ok(encoding),
+    };
+    if let Some(format) = matches.value_of("FORMAT") {
+        encoding.format = format.to_string();
+    }
+    if let Some(quality) = matches.value_of("QUALITY") {
+        encoding.quality = match quality.parse() {
+            Ok(quality @ 1..=100) => quality,
+            _ => return Err(invalid("Quality must be a number from 1 to 100.")),
+        };
+    }
+    if let Some(compression) = matches.value_of("COMPRESSION") {
+        encoding.png_compression = compression.to_string();
+    }
+    if matches.is_present("LOSSLESS") {
+        encoding.webp_lossless = true;
+    } else if matches.is_present("NO_LOSSLESS") {
+        encoding.webp_lossless = false;
+    }
+    if matches.is_present("OPTIMIZE") {
+        encoding.optimize_png = true;
+    } else if matches.is_present("NO_OPTIMIZE") {
+        encoding.optimize_png = false;
+    }
+    Ok(encoding)
+}
+
+/// Subcommand recording a region of the screen.
+fn record_command() -> clap::App<'static, 'static> {
+    use clap::Arg;
+    region_args(clap::SubCommand::with_name("record"))
+        .about("Records a region of the screen as an animation and returns it's link.")
+        .arg(
+            Arg::with_name("STORAGE")
+                .short("s")
+                .long("storage")
+                .takes_value(true)
+                .possible_values(&["local", "s3", "upload"])
+                .default_value("upload")
+                .help("Where to save the recording."),
+        )
+        .arg(
+            Arg::with_name("FORMAT")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&lib::record::FORMATS)
+                .help("Animation format, defaults to the configured one."),
+        )
+        .arg(
+            Arg::with_name("FPS")
+                .long("fps")
+                .takes_value(true)
+                .help("Frames captured per second."),
+        )
+        .arg(
+            Arg::with_name("DURATION")
+                .short("t")
+                .long("duration")
+                .takes_value(true)
+                .value_name("SECS")
+                .help("Stop recording after this many seconds."),
+        )
+        .arg(
+            Arg::with_name("STOP")
+                .long("stop")
+                .help("Stop the running recording, e.g. from a keyboard shortcut."),
+        )
+}
+
+/// Display and region chosen with the options added by `region_args`.
+fn region(matches: &clap::ArgMatches) -> lib::error::Result<(lib::img::Displays, lib::Region)> {
+    let displays = match matches.value_of("DISPLAY") {
+        Some(display) => lib::img::Displays::Index(match display.parse() {
+            Ok(index) => index,
+            Err(_) => lib::windows::monitor_index(display)
+                .context(format!("Couldn't find display {}", display))?,
+        }),
+        None => lib::img::Displays::Primary,
+    };
+    let region = if let Some(region) = matches.value_of("REGION") {
+        lib::Region::parse(region).ok_or_else(|| {
+            invalid("Region must be x,y,w,h in pixels, with a non-zero width and height.")
+        })?
+    } else if matches.is_present("FULLSCREEN") {
+        lib::Region::Fullscreen
+    } else if matches.is_present("LAST_REGION") {
+        lib::Region::Last
+    } else if let Some(window) = matches.value_of("WINDOW") {
+        lib::Region::Window(window.to_string())
+    } else {
+        lib::Region::Pick
+    };
+    Ok((displays, region))
+}
+
+/// Capture options for the screenshot subcommands.
+fn capture_options(matches: Option<&clap::ArgMatches>) -> lib::error::Result<lib::CaptureOptions> {
+    let mut options = lib::CaptureOptions::new(encoding(matches)?);
+    options.redact = lib::config::config()?.redact;
+    let matches = match matches {
+        Some(matches) => matches,
+        None => return Ok(options),
+    };
+    let (displays, region) = region(matches)?;
+    options.displays = displays;
+    options.region = region;
+    if matches.is_present("ALL_DISPLAYS") {
+        options.displays = lib::img::Displays::All;
+    }
+    options.annotate = matches.is_present("ANNOTATE");
+    if matches.is_present("REDACT") {
+        options.redact = true;
+    } else if matches.is_present("NO_REDACT") {
+        options.redact = false;
+    }
+    if matches.is_present("DELAY") {
+        options.delay = seconds(matches, "DELAY")?;
+    }
+    options.countdown = matches.is_present("COUNTDOWN");
+    Ok(options)
+}
+
+/// Takes the screenshots asked for by a screenshot subcommand with `capture`.
+fn take_screenshots(
+    matches: Option<&clap::ArgMatches>,
+    capture: fn(&lib::CaptureOptions) -> lib::error::Result<String>,
+) -> lib::error::Result<String> {
+    let options = capture_options(matches)?;
+    let matches = match matches {
+        Some(matches) if matches.is_present("COUNT") => matches,
+        _ => return capture(&options),
+    };
+    let count = matches
+        .value_of("COUNT")
+        .unwrap()
+        .parse()
+        .map_err(|_| invalid("Count must be a number."))?;
+    let interval = seconds(matches, "INTERVAL")?;
+    // Reported as they're taken, a series can run for hours.
+    let results = lib::series(&options, interval, count, |options| {
+        let result = capture(options);
+        match &result {
+            Ok(message) => println!("{}", message),
+            Err(e) if e.class() == Class::Cancelled => {}
+            Err(e) => eprintln!("Error: {}", e),
+        }
+        result
+    });
+    // Failures were reported above, the first one decides the exit code.
+    match results.into_iter().find_map(|result| result.err()) {
+        Some(e) => std::process::exit(e.exit_code()),
+        None => Ok(String::new()),
+    }
+}
+
+/// Reports `e` and exits with the code of its class. Cancelling isn't an
+/// error worth reporting, its exit code says enough.
+fn fail(e: Error) -> ! {
+    if e.class() != Class::Cancelled {
+        eprintln!("Error: {}", e);
+        if let Some(hint) = e.class().hint() {
+            eprintln!("{}", hint);
+        }
+    }
+    std::process::exit(e.exit_code());
+}
+
 fn main() {
     let matches = clap_app!(sampic =>
         (version: "0.2.0")
         (about: "Takes pictures and generates links")
         (@setting SubcommandRequiredElseHelp)
         (@setting ColoredHelp)
-        (@subcommand local =>
-            (about: "Takes a screenshot, saves it locally and returns it's path.")
-        )
-        (@subcommand s3 =>
-            (about: "Takes a screenshot, saves it in s3 and returns it's link.")
-        )
-        (@subcommand upload =>
-            (about: "Takes a screenshot, sends it to sampic and returns it's link.")
+        (@subcommand push =>
+            (about: "Saves an existing image instead of a screenshot and returns it's link.")
+            (@arg STORAGE: -s --storage +takes_value possible_value[local s3 upload] default_value("upload")
+                "Where to save the image.")
+            (@arg FILE: +required "PNG, JPEG, GIF, WebP, AVIF or BMP file, or - to read stdin.")
         )
         (@subcommand server =>
             (about: "Runs a sampic server.")
         )
+        (@subcommand delete =>
+            (about: "Deletes a screenshot from where it was saved.")
+            (@arg STORAGE: -s --storage +takes_value possible_value[local s3 upload] default_value("upload")
+                "Where the screenshot was saved.")
+            (@arg TOKEN: -t --token +takes_value "Deletion token the sampic server returned for the upload.")
+            (@arg TARGET: +required "URL or name of the screenshot.")
+        )
+        (@subcommand history =>
+            (about: "Browse screenshots taken on this machine.")
+            (@setting ColoredHelp)
+            (@subcommand list =>
+                (about: "List past screenshots, newest first.")
+                (@arg COUNT: -n --count +takes_value "Only show this many entries.")
+            )
+            (@subcommand search =>
+                (about: "List past screenshots whose URL, name or hash contains QUERY.")
+                (@arg QUERY: +required "Text to look for.")
+            )
+            (@subcommand open =>
+                (about: "Open a past screenshot.")
+                (@arg ID: +required "Id of the history entry.")
+            )
+            (@subcommand copy =>
+                (about: "Copy a past screenshot's URL to the clipboard.")
+                (@arg ID: +required "Id of the history entry.")
+            )
+            (@subcommand delete =>
+                (about: "Delete a past screenshot from its storage and from the history.")
+                (@arg ID: +required "Id of the history entry.")
+            )
+        )
+        (@subcommand keys =>
+            (about: "Manage the API keys accepted by sampic server.")
+            (@setting SubcommandRequiredElseHelp)
+            (@setting ColoredHelp)
+            (@subcommand add =>
+                (about: "Create a new API key and print its token.")
+                (@arg NAME: +required "Name to identify the key by.")
+            )
+            (@subcommand revoke =>
+                (about: "Revoke an API key so it can no longer upload.")
+                (@arg NAME: +required "Name of the key to revoke.")
+            )
+            (@subcommand list =>
+                (about: "List API keys.")
+            )
+        )
         (@subcommand config =>
             (about: "Manage sampic configuration.")
             (@setting SubcommandRequiredElseHelp)
@@ -44,15 +436,199 @@ fn main() {
             )
         )
     )
+    .subcommand(screenshot_command(
+        "local",
+        "Takes a screenshot, saves it locally and returns it's path.",
+    ))
+    .subcommand(screenshot_command(
+        "s3",
+        "Takes a screenshot, saves it in s3 and returns it's link.",
+    ))
+    .subcommand(screenshot_command(
+        "upload",
+        "Takes a screenshot, sends it to sampic and returns it's link.",
+    ))
+    .subcommand(record_command())
+    .subcommand(
+        clap::SubCommand::with_name("clipboard")
+            .about("Saves the image on the clipboard and replaces it with it's link.")
+            .arg(
+                clap::Arg::with_name("STORAGE")
+                    .short("s")
+                    .long("storage")
+                    .takes_value(true)
+                    .possible_values(&["local", "s3", "upload"])
+                    .default_value("upload")
+                    .help("Where to save the image."),
+            )
+            .arg(
+                clap::Arg::with_name("FORMAT")
+                    .short("f")
+                    .long("format")
+                    .takes_value(true)
+                    .possible_values(&lib::img::FORMATS)
+                    .help("Output format, defaults to the configured one."),
+            ),
+    )
     .get_matches();
-    let message: String = match matches.subcommand_name() {
-        Some("local") => lib::local_screenshot(),
-        Some("s3") => lib::s3_screenshot(),
-        Some("upload") => lib::upload_screenshot(),
-        Some("server") => rocket::ignite()
-            .mount("/", routes![lib::server::upload])
-            .launch()
-            .to_string(),
+    match run(&matches) {
+        Ok(message) if message.is_empty() => {}
+        Ok(message) => println!("{}", message),
+        Err(e) => fail(e),
+    }
+}
+
+/// Runs the chosen subcommand, returning what to print.
+fn run(matches: &clap::ArgMatches) -> lib::error::Result<String> {
+    match matches.subcommand_name() {
+        Some("local") => {
+            take_screenshots(matches.subcommand_matches("local"), lib::local_screenshot)
+        }
+        Some("s3") => take_screenshots(matches.subcommand_matches("s3"), lib::s3_screenshot),
+        Some("upload") => {
+            take_screenshots(matches.subcommand_matches("upload"), lib::upload_screenshot)
+        }
+        Some("record") => {
+            let record_matches = matches.subcommand_matches("record").unwrap();
+            if record_matches.is_present("STOP") {
+                lib::record::stop().context("Couldn't stop the recording")?;
+                return Ok("Stopping recording".to_string());
+            }
+            let cfg = lib::config::config()?;
+            let mut options = lib::record::RecordOptions::from_config(&cfg);
+            let (displays, region) = region(record_matches)?;
+            options.displays = displays;
+            options.region = region;
+            if let Some(format) = record_matches.value_of("FORMAT") {
+                options.format = format.to_string();
+            }
+            if let Some(fps) = record_matches.value_of("FPS") {
+                let fps: u32 = fps.parse().map_err(|_| invalid("FPS must be a number."))?;
+                options.fps = fps.max(1).min(60);
+            }
+            if record_matches.is_present("DURATION") {
+                options.duration = seconds(record_matches, "DURATION")?;
+            }
+            lib::record(record_matches.value_of("STORAGE").unwrap(), &options)
+        }
+        Some("clipboard") => {
+            let clipboard_matches = matches.subcommand_matches("clipboard");
+            lib::clipboard(
+                clipboard_matches.unwrap().value_of("STORAGE").unwrap(),
+                &encoding(clipboard_matches)?,
+            )
+        }
+        Some("push") => {
+            let push_matches = matches.subcommand_matches("push").unwrap();
+            lib::push(
+                push_matches.value_of("STORAGE").unwrap(),
+                push_matches.value_of("FILE").unwrap(),
+            )
+        }
+        Some("server") => {
+            let keys = lib::keys::KeyStore::open().context("Couldn't locate the API key file")?;
+            let cfg = lib::config::config()?;
+            let limiter = lib::limits::Limiter::open(
+                lib::limits::Limits::per_ip(&cfg),
+                lib::limits::Limits::per_key(&cfg),
+            )
+            .context("Couldn't load rate limit counters")?
+            .trust_proxy(cfg.trust_proxy);
+            let store = lib::server::Store::from_config(&cfg)
+                .context("Couldn't set up the configured storage backend")?;
+            let e = lib::server::rocket(keys, limiter, store).launch();
+            Err(Error::new(
+                Class::Other,
+                format!("Couldn't start the server: {}", e),
+            ))
+        }
+        Some("delete") => {
+            let delete_matches = matches.subcommand_matches("delete").unwrap();
+            let storage = delete_matches.value_of("STORAGE").unwrap();
+            let target = delete_matches.value_of("TARGET").unwrap();
+            let token = delete_matches
+                .value_of("TOKEN")
+                .map(|token| token.to_string());
+            lib::delete(storage, target, token).context(format!("Couldn't delete {}", target))?;
+            Ok(format!("Deleted {}", target))
+        }
+        Some("history") => {
+            let subcommand = matches.subcommand_matches("history").unwrap();
+            let history =
+                lib::history::History::open().context("Couldn't locate the history file")?;
+            let entry = |matches: &clap::ArgMatches| -> lib::error::Result<lib::history::Entry> {
+                let id = matches
+                    .value_of("ID")
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| invalid("ID must be a number"))?;
+                Ok(history.find(id)?)
+            };
+            let listing = |entries: Vec<lib::history::Entry>| -> String {
+                entries
+                    .iter()
+                    .map(|entry| entry.to_string())
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            };
+            match subcommand.subcommand() {
+                ("search", Some(search_matches)) => {
+                    let query = search_matches.value_of("QUERY").unwrap();
+                    Ok(listing(history.search(query)?))
+                }
+                ("open", Some(open_matches)) => {
+                    let entry = entry(open_matches)?;
+                    lib::open(&entry.url).context(format!("Couldn't open {}", entry.url))?;
+                    Ok(format!("Opened {}", entry.url))
+                }
+                ("copy", Some(copy_matches)) => {
+                    let entry = entry(copy_matches)?;
+                    lib::copy(&entry.url)?;
+                    Ok(format!("Copied {}", entry.url))
+                }
+                ("delete", Some(delete_matches)) => {
+                    let entry = entry(delete_matches)?;
+                    let deleted = lib::delete_from_history(entry.id)
+                        .context(format!("Couldn't delete {}", entry.url))?;
+                    Ok(format!("Deleted {}", deleted.url))
+                }
+                (_, list_matches) => {
+                    let count = match list_matches.and_then(|matches| matches.value_of("COUNT")) {
+                        Some(count) => count
+                            .parse()
+                            .map_err(|_| invalid("Count must be a number."))?,
+                        None => usize::MAX,
+                    };
+                    let entries = history.list()?;
+                    Ok(listing(entries.into_iter().take(count).collect()))
+                }
+            }
+        }
+        Some("keys") => {
+            let subcommand = matches.subcommand_matches("keys").unwrap();
+            let keys = lib::keys::KeyStore::open().context("Couldn't locate the API key file")?;
+            match subcommand.subcommand() {
+                ("add", Some(add_matches)) => {
+                    let name = add_matches.value_of("NAME").unwrap().to_string();
+                    Ok(keys.add(name)?)
+                }
+                ("revoke", Some(revoke_matches)) => {
+                    let name = revoke_matches.value_of("NAME").unwrap();
+                    keys.revoke(name)?;
+                    Ok(format!("Revoked {}", name))
+                }
+                ("list", _) => Ok(keys
+                    .list()?
+                    .iter()
+                    .map(|key| {
+                        let state = if key.revoked { "revoked" } else { "active" };
+                        format!("{}\t{}", key.name, state)
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n")),
+                _ => Ok("Ok".to_string()),
+            }
+        }
         Some("config") => {
             let subcommand = matches.subcommand_matches("config").unwrap();
             match subcommand.subcommand_name() {
@@ -60,19 +636,13 @@ fn main() {
                     let set_matches = subcommand.subcommand_matches("set").unwrap();
                     let name = set_matches.value_of("NAME").unwrap().to_string();
                     let value = set_matches.value_of("VALUE").unwrap().to_string();
-                    lib::config::set(name, value).unwrap();
-                    return ();
+                    lib::config::set(name, value)?;
+                    Ok(String::new())
                 }
-                Some("list") => {
-                    let list: String = lib::config::list().unwrap();
-                    println!("{}", list);
-                    return ();
-                }
-                Some(_) | None => "Ok".to_string(),
-            };
-            return ();
+                Some("list") => Ok(lib::config::list()?),
+                Some(_) | None => Ok("Ok".to_string()),
+            }
         }
-        Some(_) | None => "Do something!".to_string(),
-    };
-    println!("{}", message);
+        Some(_) | None => Ok("Do something!".to_string()),
+    }
 }
@@ -0,0 +1,143 @@
+//! Files sampic keeps its state in, like the history, the API keys and the
+//! server's usage counters. Each one is a TOML table that is read whole and
+//! written back whole after every change.
+use directories_next::ProjectDirs;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug)]
+pub enum DataError {
+    IOError(io::Error),
+    ParseError(toml::de::Error),
+    SerializeError(toml::ser::Error),
+}
+
+impl From<io::Error> for DataError {
+    fn from(e: io::Error) -> DataError {
+        DataError::IOError(e)
+    }
+}
+
+impl From<toml::de::Error> for DataError {
+    fn from(e: toml::de::Error) -> DataError {
+        DataError::ParseError(e)
+    }
+}
+
+impl From<toml::ser::Error> for DataError {
+    fn from(e: toml::ser::Error) -> DataError {
+        DataError::SerializeError(e)
+    }
+}
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataError::IOError(e) => write!(f, "IO error: {}", e),
+            DataError::ParseError(e) => write!(f, "Malformed file: {}", e),
+            DataError::SerializeError(e) => write!(f, "Couldn't write the file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DataError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DataError::IOError(e) => Some(e),
+            DataError::ParseError(e) => Some(e),
+            DataError::SerializeError(e) => Some(e),
+        }
+    }
+}
+
+/// sampic's configuration and data directories.
+pub fn dirs() -> io::Result<ProjectDirs> {
+    ProjectDirs::from("rs", "", "sampic")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No home directory"))
+}
+
+pub fn data_dir() -> Result<PathBuf, DataError> {
+    Ok(dirs()?.data_dir().to_path_buf())
+}
+
+pub fn config_dir() -> Result<PathBuf, DataError> {
+    Ok(dirs()?.config_dir().to_path_buf())
+}
+
+/// Reads the table at `path`, or an empty one if the file doesn't exist yet.
+pub fn load<T: DeserializeOwned + Default>(path: &Path) -> Result<T, DataError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(toml::from_str(&contents)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `table` to `path`, creating its directory if needed. The table is
+/// written next to it first and then renamed over it, so a crash halfway
+/// leaves the previous table rather than a truncated one.
+///
+/// Only the owner may read the file, since API keys and deletion tokens are
+/// kept in some of them.
+pub fn save<T: Serialize>(path: &Path, table: &T) -> Result<(), DataError> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let contents = toml::to_string(table)?;
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    // Permissions are only set when a file is created.
+    fs::remove_file(&temporary).ok();
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(&temporary)?.write_all(contents.as_bytes())?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn tables_are_saved_whole_and_private() {
+        let dir = std::env::temp_dir().join(format!("sampic-datafile-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let path = dir.join("table.toml");
+        let empty: HashMap<String, u64> = load(&path).unwrap();
+        assert!(empty.is_empty());
+
+        let mut table = HashMap::new();
+        table.insert("answer".to_string(), 42u64);
+        save(&path, &table).unwrap();
+        save(&path, &table).unwrap();
+        assert_eq!(load::<HashMap<String, u64>>(&path).unwrap(), table);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,239 @@
+//! Error returned by sampic's public API. It keeps the error that caused it
+//! and what sampic was doing at the time, and sorts errors into classes so
+//! the command line can exit with a code scripts can rely on.
+use super::config::ConfigError;
+use super::history::HistoryError;
+use super::keys::KeyError;
+use super::limits::LimitError;
+use super::record::RecordError;
+use super::redact::RedactError;
+use super::region::GuiError;
+use super::storage::StorageError;
+use super::windows::WindowError;
+use std::fmt;
+
+/// Kinds of failure, each with its own exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Class {
+    /// Anything not covered below.
+    Other,
+    /// The user dismissed the region selector or the editor.
+    Cancelled,
+    /// The configuration is missing something or is invalid.
+    Config,
+    /// The storage backend couldn't be reached.
+    Network,
+    /// The storage backend rejected sampic's credentials.
+    Auth,
+    /// The storage backend failed to save, find or delete an image.
+    Storage,
+    /// The screen couldn't be captured or processed.
+    Capture,
+    /// A file, argument or history entry given on the command line is unusable.
+    Input,
+}
+
+impl Class {
+    /// Process exit code, stable across releases.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Class::Other => 1,
+            Class::Cancelled => 2,
+            Class::Config => 3,
+            Class::Network => 4,
+            Class::Auth => 5,
+            Class::Storage => 6,
+            Class::Capture => 7,
+            Class::Input => 8,
+        }
+    }
+
+    /// What the user can do about it, if anything.
+    pub fn hint(self) -> Option<&'static str> {
+        match self {
+            Class::Config => Some("Check your settings with `sampic config list`."),
+            Class::Network => {
+                Some("Check your connection and the sampic_endpoint or endpoint setting.")
+            }
+            Class::Auth => Some("Check sampic_token, or api_key and api_secret_key for S3."),
+            _ => None,
+        }
+    }
+}
+
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug)]
+pub struct Error {
+    class: Class,
+    /// What sampic was doing, e.g. "Couldn't save the screenshot".
+    context: String,
+    source: Option<Source>,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    pub fn new<C: Into<String>>(class: Class, context: C) -> Self {
+        Error {
+            class,
+            context: context.into(),
+            source: None,
+        }
+    }
+
+    pub fn cancelled() -> Self {
+        Error::new(Class::Cancelled, "Cancelled")
+    }
+
+    pub fn with_source<E: Into<Source>>(mut self, source: E) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn class(&self) -> Class {
+        self.class
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.class.exit_code()
+    }
+
+    fn caused_by<E: std::error::Error + Send + Sync + 'static>(class: Class, source: E) -> Self {
+        Error::new(class, source.to_string()).with_source(source)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.context)?;
+        // Errors converted with `From` already say what their source says.
+        match &self.source {
+            Some(source) if source.to_string() != self.context => write!(f, ": {}", source),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// Adds what was being done to an error, keeping it as the source.
+pub trait Context<T> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T>;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|e| {
+            let e = e.into();
+            Error::new(e.class, context).with_source(e)
+        })
+    }
+}
+
+impl From<StorageError> for Error {
+    fn from(e: StorageError) -> Error {
+        let class = match e {
+            StorageError::ConfigError(_) => Class::Config,
+            StorageError::CredentialsError(_)
+            | StorageError::Unauthorized
+            | StorageError::Forbidden => Class::Auth,
+            StorageError::NetworkError(_) => Class::Network,
+            _ => Class::Storage,
+        };
+        Error::caused_by(class, e)
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Error {
+        Error::caused_by(Class::Config, e)
+    }
+}
+
+impl From<HistoryError> for Error {
+    fn from(e: HistoryError) -> Error {
+        let class = match e {
+            HistoryError::NotFound => Class::Input,
+            _ => Class::Other,
+        };
+        Error::caused_by(class, e)
+    }
+}
+
+impl From<KeyError> for Error {
+    fn from(e: KeyError) -> Error {
+        let class = match e {
+            KeyError::NameTaken | KeyError::NotFound => Class::Input,
+            _ => Class::Other,
+        };
+        Error::caused_by(class, e)
+    }
+}
+
+impl From<WindowError> for Error {
+    fn from(e: WindowError) -> Error {
+        let class = match e {
+            WindowError::NotFound | WindowError::NoMonitor => Class::Input,
+            _ => Class::Capture,
+        };
+        Error::caused_by(class, e)
+    }
+}
+
+impl From<RedactError> for Error {
+    fn from(e: RedactError) -> Error {
+        let class = match e {
+            RedactError::Unsupported | RedactError::InvalidPattern(_) => Class::Config,
+            RedactError::OcrError => Class::Capture,
+        };
+        Error::caused_by(class, e)
+    }
+}
+
+impl From<LimitError> for Error {
+    fn from(e: LimitError) -> Error {
+        Error::caused_by(Class::Other, e)
+    }
+}
+
+impl From<GuiError> for Error {
+    fn from(e: GuiError) -> Error {
+        Error::caused_by(Class::Capture, e)
+    }
+}
+
+impl From<RecordError> for Error {
+    fn from(e: RecordError) -> Error {
+        Error::caused_by(Class::Capture, e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Error {
+        Error::caused_by(Class::Other, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_keeps_the_class_and_source() {
+        let failed: std::result::Result<(), StorageError> = Err(StorageError::Unauthorized);
+        let e = failed.context("Couldn't save the screenshot").unwrap_err();
+        assert_eq!(e.class(), Class::Auth);
+        assert_eq!(e.exit_code(), 5);
+        assert!(e
+            .to_string()
+            .starts_with("Couldn't save the screenshot: Unauthorized"));
+        assert!(std::error::Error::source(&e).is_some());
+    }
+}
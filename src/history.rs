@@ -0,0 +1,331 @@
+//! Local record of every screenshot taken. Kept in `history.toml` in sampic's
+//! data directory, with a small thumbnail of each capture next to it.
+use super::datafile::{self, now, DataError};
+use super::naming;
+use super::storage::Saved;
+use image::imageops::FilterType;
+use image::{ImageBuffer, Rgba};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+
+const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Data(DataError),
+    NotFound,
+}
+
+impl From<DataError> for HistoryError {
+    fn from(e: DataError) -> HistoryError {
+        HistoryError::Data(e)
+    }
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Data(e) => write!(f, "History file: {}", e),
+            HistoryError::NotFound => write!(f, "No history entry with that id"),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HistoryError::Data(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub id: u64,
+    pub url: String,
+    pub name: String,
+    /// Backend the screenshot was saved to, as accepted by `storage::backend`.
+    pub backend: String,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    pub width: u32,
+    pub height: u32,
+    /// SHA-256 of the captured pixels, whatever naming scheme was used, or
+    /// empty for images that couldn't be decoded.
+    pub hash: String,
+    pub thumbnail: Option<String>,
+    pub delete_token: Option<String>,
+    /// A recording or another animation, whose thumbnail is its first frame.
+    #[serde(default)]
+    pub animated: bool,
+}
+
+impl Entry {
+    /// Case insensitive match against the URL, name, backend and hash.
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [&self.url, &self.name, &self.backend, &self.hash]
+            .iter()
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}x{}{}\t{}",
+            self.id,
+            age(self.timestamp),
+            self.backend,
+            self.width,
+            self.height,
+            if self.animated { " animated" } else { "" },
+            self.url
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HistoryTable {
+    next_id: u64,
+    entries: Vec<Entry>,
+}
+
+pub struct History {
+    dir: PathBuf,
+}
+
+/// Rough "how long ago" for listings, e.g. `5m ago`.
+fn age(timestamp: u64) -> String {
+    let elapsed = now().saturating_sub(timestamp);
+    match elapsed {
+        0..=59 => format!("{}s ago", elapsed),
+        60..=3599 => format!("{}m ago", elapsed / 60),
+        3600..=86399 => format!("{}h ago", elapsed / 3600),
+        _ => format!("{}d ago", elapsed / 86400),
+    }
+}
+
+impl History {
+    /// History kept in sampic's data directory.
+    pub fn open() -> Result<Self, HistoryError> {
+        Ok(History::at(datafile::data_dir()?))
+    }
+
+    /// History kept in `dir`.
+    pub fn at(dir: PathBuf) -> Self {
+        History { dir }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join("history.toml")
+    }
+
+    fn load(&self) -> Result<HistoryTable, HistoryError> {
+        Ok(datafile::load(&self.path())?)
+    }
+
+    fn store(&self, table: &HistoryTable) -> Result<(), HistoryError> {
+        Ok(datafile::save(&self.path(), table)?)
+    }
+
+    /// Shrinks an RGBA buffer to fit in a `THUMBNAIL_SIZE` square and writes
+    /// it as a PNG in the thumbnails directory.
+    fn thumbnail(&self, id: u64, buffer: &[u8], w: u32, h: u32) -> Option<String> {
+        let image = ImageBuffer::<Rgba<u8>, &[u8]>::from_raw(w, h, buffer)?;
+        let (tw, th) = if w >= h {
+            (
+                THUMBNAIL_SIZE.min(w),
+                (h * THUMBNAIL_SIZE.min(w) / w).max(1),
+            )
+        } else {
+            (
+                (w * THUMBNAIL_SIZE.min(h) / h).max(1),
+                THUMBNAIL_SIZE.min(h),
+            )
+        };
+        let thumbnail = image::imageops::resize(&image, tw, th, FilterType::Triangle);
+        let dir = self.dir.join("thumbnails");
+        fs::create_dir_all(&dir).ok()?;
+        let path = dir.join(format!("{}.png", id));
+        thumbnail.save(&path).ok()?;
+        Some(path.display().to_string())
+    }
+
+    /// Adds a capture to the history and returns its entry. `buffer` holds
+    /// its RGBA pixels (the first frame if it's `animated`), if they could
+    /// be decoded.
+    pub fn record(
+        &self,
+        saved: &Saved,
+        backend: &str,
+        buffer: Option<&[u8]>,
+        animated: bool,
+        w: u32,
+        h: u32,
+    ) -> Result<Entry, HistoryError> {
+        let mut table = self.load()?;
+        table.next_id = table.next_id.max(1);
+        let id = table.next_id;
+        table.next_id += 1;
+        let entry = Entry {
+            id,
+            url: saved.link.clone(),
+            name: saved.name.clone(),
+            backend: backend.to_string(),
+            timestamp: now(),
+            width: w,
+            height: h,
+            hash: buffer
+                .map(|buffer| naming::name(buffer, "sha256", naming::MAX_LENGTH))
+                .unwrap_or_default(),
+            thumbnail: buffer.and_then(|buffer| self.thumbnail(id, buffer, w, h)),
+            delete_token: saved.delete_token.clone(),
+            animated,
+        };
+        table.entries.push(entry.clone());
+        self.store(&table)?;
+        Ok(entry)
+    }
+
+    /// Entries from newest to oldest.
+    pub fn list(&self) -> Result<Vec<Entry>, HistoryError> {
+        let mut entries = self.load()?.entries;
+        entries.reverse();
+        Ok(entries)
+    }
+
+    pub fn search(&self, query: &str) -> Result<Vec<Entry>, HistoryError> {
+        Ok(self
+            .list()?
+            .into_iter()
+            .filter(|entry| entry.matches(query))
+            .collect())
+    }
+
+    pub fn find(&self, id: u64) -> Result<Entry, HistoryError> {
+        self.load()?
+            .entries
+            .into_iter()
+            .find(|entry| entry.id == id)
+            .ok_or(HistoryError::NotFound)
+    }
+
+    /// Drops an entry and its thumbnail. The screenshot itself is left alone.
+    pub fn forget(&self, id: u64) -> Result<(), HistoryError> {
+        let mut table = self.load()?;
+        let index = table
+            .entries
+            .iter()
+            .position(|entry| entry.id == id)
+            .ok_or(HistoryError::NotFound)?;
+        let entry = table.entries.remove(index);
+        if let Some(thumbnail) = entry.thumbnail {
+            fs::remove_file(thumbnail).ok();
+        }
+        self.store(&table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> History {
+        let dir =
+            std::env::temp_dir().join(format!("sampic-history-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        History::at(dir)
+    }
+
+    fn saved(name: &str) -> Saved {
+        Saved {
+            link: format!("https://sampic.test/{}", name),
+            name: name.to_string(),
+            delete_token: Some("token".into()),
+        }
+    }
+
+    fn record(history: &History, name: &str) -> Entry {
+        let pixels = [200u8; 4 * 4 * 4];
+        history
+            .record(&saved(name), "upload", Some(&pixels), false, 4, 4)
+            .unwrap()
+    }
+
+    #[test]
+    fn entries_are_listed_newest_first() {
+        let history = scratch("ledger");
+        assert!(history.list().unwrap().is_empty());
+        let first = record(&history, "first.png");
+        let second = record(&history, "second.png");
+        assert_eq!((first.id, second.id), (1, 2));
+        let ids: Vec<u64> = history.list().unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, [2, 1]);
+
+        let found = history.find(1).unwrap();
+        assert_eq!(found.url, "https://sampic.test/first.png");
+        assert_eq!(found.backend, "upload");
+        assert_eq!(found.delete_token.as_deref(), Some("token"));
+        assert_eq!(found.hash.len(), naming::MAX_LENGTH);
+        let searched: Vec<u64> = history
+            .search("SECOND")
+            .unwrap()
+            .iter()
+            .map(|e| e.id)
+            .collect();
+        assert_eq!(searched, [2]);
+        // Both have the same pixels.
+        assert_eq!(history.search(&found.hash).unwrap().len(), 2);
+        fs::remove_dir_all(&history.dir).ok();
+    }
+
+    #[test]
+    fn ids_are_not_reused_once_forgotten() {
+        let history = scratch("ids");
+        record(&history, "a.png");
+        let b = record(&history, "b.png");
+        history.forget(b.id).unwrap();
+        assert!(matches!(history.find(b.id), Err(HistoryError::NotFound)));
+        assert!(matches!(history.forget(b.id), Err(HistoryError::NotFound)));
+        assert_eq!(record(&history, "c.png").id, 3);
+        fs::remove_dir_all(&history.dir).ok();
+    }
+
+    #[test]
+    fn thumbnails_fit_in_a_square_and_go_with_their_entry() {
+        let history = scratch("thumbnails");
+        let pixels = vec![90u8; 512 * 128 * 4];
+        let entry = history
+            .record(&saved("wide.png"), "local", Some(&pixels), false, 512, 128)
+            .unwrap();
+        let thumbnail = entry.thumbnail.unwrap();
+        assert_eq!(image::image_dimensions(&thumbnail).unwrap(), (256, 64));
+        history.forget(entry.id).unwrap();
+        assert!(!std::path::Path::new(&thumbnail).exists());
+
+        // Nothing to make a thumbnail or hash from.
+        let entry = history
+            .record(&saved("still.avif"), "local", None, false, 512, 128)
+            .unwrap();
+        assert!(entry.thumbnail.is_none());
+        assert!(entry.hash.is_empty());
+        fs::remove_dir_all(&history.dir).ok();
+    }
+
+    #[test]
+    fn malformed_ledgers_are_reported() {
+        let history = scratch("malformed");
+        fs::create_dir_all(&history.dir).unwrap();
+        fs::write(history.path(), "entries = 3").unwrap();
+        assert!(matches!(
+            history.list(),
+            Err(HistoryError::Data(DataError::ParseError(_)))
+        ));
+        fs::remove_dir_all(&history.dir).ok();
+    }
+}
@@ -0,0 +1,143 @@
+//! API keys accepted by the upload server.
+//!
+//! Keys live in a `keys.toml` file next to `sampic.toml`, which only its owner
+//! can read. The file is read on every check, so adding or revoking a key
+//! takes effect without restarting the server.
+use super::datafile::{self, DataError};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use subtle::ConstantTimeEq;
+
+const TOKEN_LENGTH: usize = 40;
+
+#[derive(Debug)]
+pub enum KeyError {
+    Data(DataError),
+    NameTaken,
+    NotFound,
+}
+
+impl From<DataError> for KeyError {
+    fn from(e: DataError) -> KeyError {
+        KeyError::Data(e)
+    }
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Data(e) => write!(f, "Key file: {}", e),
+            KeyError::NameTaken => write!(f, "A key with that name already exists"),
+            KeyError::NotFound => write!(f, "No key with that name"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::Data(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Unguessable alphanumeric string of `length` characters.
+pub fn random_token(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub token: String,
+    pub revoked: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct KeyTable {
+    keys: Vec<ApiKey>,
+}
+
+/// Result of checking a bearer token against the key file.
+pub enum Verdict {
+    Valid(String),
+    Revoked,
+    Unknown,
+}
+
+pub struct KeyStore {
+    path: PathBuf,
+}
+
+impl KeyStore {
+    /// Key store kept in sampic's configuration directory.
+    pub fn open() -> Result<Self, KeyError> {
+        Ok(KeyStore {
+            path: datafile::config_dir()?.join("keys.toml"),
+        })
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        KeyStore { path }
+    }
+
+    fn load(&self) -> Result<KeyTable, KeyError> {
+        Ok(datafile::load(&self.path)?)
+    }
+
+    fn store(&self, table: &KeyTable) -> Result<(), KeyError> {
+        Ok(datafile::save(&self.path, table)?)
+    }
+
+    /// Creates a new key and returns its token.
+    pub fn add(&self, name: String) -> Result<String, KeyError> {
+        let mut table = self.load()?;
+        if table.keys.iter().any(|key| key.name == name) {
+            return Err(KeyError::NameTaken);
+        }
+        let token = random_token(TOKEN_LENGTH);
+        table.keys.push(ApiKey {
+            name,
+            token: token.clone(),
+            revoked: false,
+        });
+        self.store(&table)?;
+        Ok(token)
+    }
+
+    pub fn revoke(&self, name: &str) -> Result<(), KeyError> {
+        let mut table = self.load()?;
+        let key = table
+            .keys
+            .iter_mut()
+            .find(|key| key.name == name)
+            .ok_or(KeyError::NotFound)?;
+        key.revoked = true;
+        self.store(&table)
+    }
+
+    pub fn list(&self) -> Result<Vec<ApiKey>, KeyError> {
+        Ok(self.load()?.keys)
+    }
+
+    /// What `token` gives access to. Tokens are compared in constant time,
+    /// so how long a guess takes to be rejected doesn't tell how close it was.
+    pub fn verify(&self, token: &str) -> Result<Verdict, KeyError> {
+        let table = self.load()?;
+        let matches = |key: &ApiKey| bool::from(key.token.as_bytes().ct_eq(token.as_bytes()));
+        let verdict = match table.keys.into_iter().find(matches) {
+            Some(key) if key.revoked => Verdict::Revoked,
+            Some(key) => Verdict::Valid(key.name),
+            None => Verdict::Unknown,
+        };
+        Ok(verdict)
+    }
+}
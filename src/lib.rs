@@ -6,30 +6,81 @@
 //! - Image compression and storage
 //! - URL generation
 //! - Server endpoint
-//! - Authentication (TODO)
+//! - Authentication
+pub mod annotate;
+pub mod datafile;
+pub mod error;
+mod font;
+pub mod history;
+pub mod keys;
+pub mod limits;
+pub mod naming;
+pub mod record;
+pub mod redact;
 mod region;
+pub mod snippet;
+pub mod uploads;
+pub mod windows;
 
 extern crate piston;
 
 pub mod config {
     extern crate confy;
+    use super::img;
+    use super::naming;
+    use super::record;
+    use super::redact;
+    use super::snippet;
     use serde::{Deserialize, Serialize};
+    use std::collections::BTreeMap;
+    use std::fmt;
 
     #[derive(Debug)]
     pub enum ConfigError {
         InvalidStorageValue,
         APIKeyNotDefined,
         APISecretKeyNotDefined,
-        IOError,
+        PublicURLNotDefined,
+        IOError(std::io::Error),
     }
 
     impl From<std::io::Error> for ConfigError {
-        fn from(_: std::io::Error) -> ConfigError {
-            ConfigError::IOError
+        fn from(e: std::io::Error) -> ConfigError {
+            ConfigError::IOError(e)
+        }
+    }
+
+    impl fmt::Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                ConfigError::InvalidStorageValue => write!(f, "Unknown setting or invalid value"),
+                ConfigError::APIKeyNotDefined => {
+                    write!(f, "api_key isn't set, see `sampic config set api_key`")
+                }
+                ConfigError::APISecretKeyNotDefined => write!(
+                    f,
+                    "api_secret_key isn't set, see `sampic config set api_secret_key`"
+                ),
+                ConfigError::PublicURLNotDefined => write!(
+                    f,
+                    "public_url must be set for the server to store uploads locally, see `sampic config set public_url`"
+                ),
+                ConfigError::IOError(e) => write!(f, "Couldn't read or write sampic.toml: {}", e),
+            }
+        }
+    }
+
+    impl std::error::Error for ConfigError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                ConfigError::IOError(e) => Some(e),
+                _ => None,
+            }
         }
     }
 
     #[derive(Serialize, Deserialize)]
+    #[serde(default)]
     pub struct SampConf {
         pub api_key: String,
         pub api_secret_key: String,
@@ -38,6 +89,39 @@ pub mod config {
         pub bucket: String,
         pub local_path: String,
         pub sampic_endpoint: String,
+        pub sampic_token: String,
+        pub naming: String,
+        pub name_length: u64,
+        // Screenshot encoding.
+        pub format: String,
+        pub quality: u64,
+        pub webp_lossless: bool,
+        pub png_compression: String,
+        pub optimize_png: bool,
+        // Server side settings.
+        pub storage: String,
+        pub public_url: String,
+        /// Whether the server is behind a reverse proxy that sets `X-Real-IP`.
+        pub trust_proxy: bool,
+        // Limits, zero means unlimited.
+        pub ip_requests_per_minute: u64,
+        pub ip_bytes_per_day: u64,
+        pub ip_stored_bytes: u64,
+        pub key_requests_per_minute: u64,
+        pub key_bytes_per_day: u64,
+        pub key_stored_bytes: u64,
+        // What's copied once an image is saved, see `snippet`.
+        pub clipboard: String,
+        pub clipboard_template: String,
+        // Screen recordings, see `record`.
+        pub record_format: String,
+        pub record_fps: u64,
+        pub record_seconds: u64,
+        // Redaction, see `redact`.
+        pub redact: bool,
+        pub redact_style: String,
+        // Kept last, TOML tables can't be followed by plain values.
+        pub redact_patterns: BTreeMap<String, String>,
     }
 
     impl ::std::default::Default for SampConf {
@@ -50,6 +134,40 @@ pub mod config {
                 api_secret_key: "".into(),
                 local_path: "/tmp/".into(),
                 sampic_endpoint: "https://api.sampic.xyz/upload".to_string(),
+                sampic_token: "".into(),
+                naming: "sha256".into(),
+                name_length: 32,
+                format: "png".into(),
+                quality: 85,
+                webp_lossless: false,
+                png_compression: "default".into(),
+                optimize_png: false,
+                storage: "s3".into(),
+                public_url: "".into(),
+                trust_proxy: false,
+                ip_requests_per_minute: 30,
+                ip_bytes_per_day: 1_000_000_000,
+                ip_stored_bytes: 0,
+                key_requests_per_minute: 30,
+                key_bytes_per_day: 1_000_000_000,
+                key_stored_bytes: 0,
+                clipboard: "url".into(),
+                clipboard_template: "{url}".into(),
+                record_format: "gif".into(),
+                record_fps: 10,
+                record_seconds: 60,
+                redact: false,
+                redact_style: "black".into(),
+                redact_patterns: redact::default_patterns(),
+            }
+        }
+    }
+
+    impl SampConf {
+        pub fn naming(&self) -> naming::Naming {
+            naming::Naming {
+                scheme: self.naming.clone(),
+                length: self.name_length as usize,
             }
         }
     }
@@ -64,23 +182,49 @@ pub mod config {
 
     pub fn list() -> Result<String, ConfigError> {
         let cfg = config()?;
-        return Ok(format!(
-            "{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n{}={}\n",
-            "api_key",
-            cfg.api_key,
-            "api_secret_key",
-            cfg.api_secret_key,
-            "region",
-            cfg.region,
-            "endpoint",
-            cfg.endpoint,
-            "bucket",
-            cfg.bucket,
-            "local_path",
-            cfg.local_path,
-            "sampic_endpoint",
-            cfg.sampic_endpoint
-        ));
+        let values = vec![
+            ("api_key", cfg.api_key),
+            ("api_secret_key", cfg.api_secret_key),
+            ("region", cfg.region),
+            ("endpoint", cfg.endpoint),
+            ("bucket", cfg.bucket),
+            ("local_path", cfg.local_path),
+            ("sampic_endpoint", cfg.sampic_endpoint),
+            ("sampic_token", cfg.sampic_token),
+            ("naming", cfg.naming),
+            ("name_length", cfg.name_length.to_string()),
+            ("format", cfg.format),
+            ("quality", cfg.quality.to_string()),
+            ("webp_lossless", cfg.webp_lossless.to_string()),
+            ("png_compression", cfg.png_compression),
+            ("optimize_png", cfg.optimize_png.to_string()),
+            ("storage", cfg.storage),
+            ("public_url", cfg.public_url),
+            ("trust_proxy", cfg.trust_proxy.to_string()),
+            (
+                "ip_requests_per_minute",
+                cfg.ip_requests_per_minute.to_string(),
+            ),
+            ("ip_bytes_per_day", cfg.ip_bytes_per_day.to_string()),
+            ("ip_stored_bytes", cfg.ip_stored_bytes.to_string()),
+            (
+                "key_requests_per_minute",
+                cfg.key_requests_per_minute.to_string(),
+            ),
+            ("key_bytes_per_day", cfg.key_bytes_per_day.to_string()),
+            ("key_stored_bytes", cfg.key_stored_bytes.to_string()),
+            ("clipboard", cfg.clipboard),
+            ("clipboard_template", cfg.clipboard_template),
+            ("record_format", cfg.record_format),
+            ("record_fps", cfg.record_fps.to_string()),
+            ("record_seconds", cfg.record_seconds.to_string()),
+            ("redact", cfg.redact.to_string()),
+            ("redact_style", cfg.redact_style),
+        ];
+        return Ok(values
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, value))
+            .collect());
     }
 
     pub fn api_secret_key() -> Result<String, ConfigError> {
@@ -101,6 +245,14 @@ pub mod config {
         return Ok(cfg);
     }
 
+    fn number(value: &str) -> Result<u64, ConfigError> {
+        value.parse().map_err(|_| ConfigError::InvalidStorageValue)
+    }
+
+    fn flag(value: &str) -> Result<bool, ConfigError> {
+        value.parse().map_err(|_| ConfigError::InvalidStorageValue)
+    }
+
     pub fn set(key: String, value: String) -> Result<(), ConfigError> {
         let mut cfg = config()?;
         match key.as_str() {
@@ -125,6 +277,110 @@ pub mod config {
             "sampic_endpoint" => {
                 cfg.sampic_endpoint = value;
             }
+            "sampic_token" => {
+                cfg.sampic_token = value;
+            }
+            "naming" => {
+                if !naming::SCHEMES.contains(&value.as_str()) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.naming = value;
+            }
+            "name_length" => {
+                let length = number(&value)?;
+                let lengths = naming::MIN_LENGTH as u64..=naming::MAX_LENGTH as u64;
+                if !lengths.contains(&length) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.name_length = length;
+            }
+            "format" => {
+                if !img::FORMATS.contains(&value.as_str()) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.format = value;
+            }
+            "quality" => {
+                cfg.quality = number(&value)?;
+                if cfg.quality == 0 || cfg.quality > 100 {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+            }
+            "webp_lossless" => {
+                cfg.webp_lossless = flag(&value)?;
+            }
+            "png_compression" => {
+                if !img::PNG_COMPRESSION.contains(&value.as_str()) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.png_compression = value;
+            }
+            "optimize_png" => {
+                cfg.optimize_png = flag(&value)?;
+            }
+            "storage" => {
+                cfg.storage = value;
+            }
+            "public_url" => {
+                cfg.public_url = value;
+            }
+            "trust_proxy" => {
+                cfg.trust_proxy = flag(&value)?;
+            }
+            "ip_requests_per_minute" => {
+                cfg.ip_requests_per_minute = number(&value)?;
+            }
+            "ip_bytes_per_day" => {
+                cfg.ip_bytes_per_day = number(&value)?;
+            }
+            "ip_stored_bytes" => {
+                cfg.ip_stored_bytes = number(&value)?;
+            }
+            "key_requests_per_minute" => {
+                cfg.key_requests_per_minute = number(&value)?;
+            }
+            "key_bytes_per_day" => {
+                cfg.key_bytes_per_day = number(&value)?;
+            }
+            "key_stored_bytes" => {
+                cfg.key_stored_bytes = number(&value)?;
+            }
+            "clipboard" => {
+                if !snippet::ACTIONS.contains(&value.as_str()) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.clipboard = value;
+            }
+            "clipboard_template" => {
+                cfg.clipboard_template = value;
+            }
+            "record_format" => {
+                if !record::FORMATS.contains(&value.as_str()) {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.record_format = value;
+            }
+            "record_fps" => {
+                cfg.record_fps = number(&value)?;
+                if cfg.record_fps == 0 || cfg.record_fps > 60 {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+            }
+            "record_seconds" => {
+                cfg.record_seconds = number(&value)?;
+                if cfg.record_seconds == 0 || cfg.record_seconds > record::MAX_SECONDS {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+            }
+            "redact" => {
+                cfg.redact = flag(&value)?;
+            }
+            "redact_style" => {
+                if value != "black" && value != "blur" {
+                    return Err(ConfigError::InvalidStorageValue);
+                }
+                cfg.redact_style = value;
+            }
             _ => return Err(ConfigError::InvalidStorageValue),
         };
         confy::store("sampic", cfg)?;
@@ -134,69 +390,87 @@ pub mod config {
 
 pub mod storage {
     extern crate futures;
-    use std::collections::hash_map::DefaultHasher;
+    use super::config;
+    use super::img;
+    use super::naming;
+    use super::server::Uploaded;
     use std::fmt;
     use std::fs;
-    use std::hash::Hasher;
+    use std::io;
     use std::io::prelude::*;
     use std::path::Path;
     use std::path::PathBuf;
-    extern crate image;
-    use super::config;
-    use std::io;
     extern crate rusoto_core;
     extern crate rusoto_credential;
     extern crate rusoto_s3;
     use rusoto_core::request::{HttpClient, TlsError};
     use rusoto_core::{Region, RusotoError};
     use rusoto_credential::StaticProvider;
-    use rusoto_s3::{GetObjectRequest, PutObjectRequest, S3Client, S3};
+    use rusoto_s3::{
+        DeleteObjectRequest, GetObjectError, GetObjectRequest, PutObjectRequest, S3Client, S3,
+    };
     use tokio::io::AsyncReadExt;
 
     #[derive(Debug)]
     pub enum StorageError {
         SaveError,
-        IOError,
+        IOError(io::Error),
         ReadError,
-        ConfigError,
-        CredentialsError,
-        UnknownError,
+        ConfigError(config::ConfigError),
+        CredentialsError(String),
+        /// The backend couldn't be reached.
+        NetworkError(Box<dyn std::error::Error + Send + Sync>),
+        NotFound,
+        Unauthorized,
+        Forbidden,
+        /// Any other failure the backend reported, as it described it.
+        UnknownError(String),
     }
 
     impl From<TlsError> for StorageError {
-        fn from(_: TlsError) -> StorageError {
-            StorageError::IOError
+        fn from(e: TlsError) -> StorageError {
+            StorageError::NetworkError(Box::new(e))
         }
     }
 
-    impl<T> From<RusotoError<T>> for StorageError {
+    impl<T: std::error::Error + 'static> From<RusotoError<T>> for StorageError {
         fn from(e: RusotoError<T>) -> StorageError {
             match e {
-                RusotoError::Service(_) => StorageError::UnknownError,
-                RusotoError::HttpDispatch(_) => StorageError::IOError,
-                RusotoError::Credentials(_) => StorageError::CredentialsError,
-                RusotoError::Validation(_)
-                | RusotoError::ParseError(_)
-                | RusotoError::Unknown(_)
-                | RusotoError::Blocking => StorageError::UnknownError,
+                RusotoError::HttpDispatch(e) => StorageError::NetworkError(Box::new(e)),
+                RusotoError::Credentials(e) => StorageError::CredentialsError(e.to_string()),
+                RusotoError::Unknown(ref response) if response.status.as_u16() == 403 => {
+                    StorageError::Forbidden
+                }
+                e => StorageError::UnknownError(e.to_string()),
             }
         }
     }
     impl From<std::io::Error> for StorageError {
-        fn from(_: std::io::Error) -> StorageError {
-            StorageError::IOError
+        fn from(e: std::io::Error) -> StorageError {
+            StorageError::IOError(e)
         }
     }
 
     impl From<config::ConfigError> for StorageError {
-        fn from(_: config::ConfigError) -> StorageError {
-            StorageError::ConfigError
+        fn from(e: config::ConfigError) -> StorageError {
+            StorageError::ConfigError(e)
         }
     }
 
     impl From<minreq::Error> for StorageError {
-        fn from(_: minreq::Error) -> StorageError {
-            StorageError::ReadError
+        fn from(e: minreq::Error) -> StorageError {
+            StorageError::NetworkError(Box::new(e))
+        }
+    }
+
+    impl std::error::Error for StorageError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                StorageError::IOError(e) => Some(e),
+                StorageError::ConfigError(e) => Some(e),
+                StorageError::NetworkError(e) => Some(e.as_ref()),
+                _ => None,
+            }
         }
     }
 
@@ -204,61 +478,116 @@ pub mod storage {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
             match self {
                 StorageError::SaveError => write!(f, "Save error"),
-                StorageError::IOError => write!(f, "IO error"),
+                StorageError::IOError(e) => write!(f, "IO error: {}", e),
                 StorageError::ReadError => write!(f, "Read error"),
-                StorageError::UnknownError => write!(f, "Unknown error"),
-                StorageError::ConfigError => write!(f, "Configuration error"),
-                StorageError::CredentialsError => write!(f, "Configuration error"),
+                StorageError::UnknownError(message) => write!(f, "{}", message),
+                StorageError::ConfigError(e) => write!(f, "Configuration error: {}", e),
+                StorageError::CredentialsError(message) => {
+                    write!(f, "Invalid credentials: {}", message)
+                }
+                StorageError::NetworkError(e) => write!(f, "Network error: {}", e),
+                StorageError::NotFound => write!(f, "Not found"),
+                StorageError::Unauthorized => write!(
+                    f,
+                    "Unauthorized, set your token with `sampic config set sampic_token <token>`"
+                ),
+                StorageError::Forbidden => {
+                    write!(f, "The server refused the request, check your token")
+                }
             }
         }
     }
     type StorageResult<I> = Result<I, StorageError>;
 
+    /// Where a saved image ended up.
+    pub struct Saved {
+        pub link: String,
+        pub name: String,
+        /// Only set by backends that need it to delete the image later.
+        pub delete_token: Option<String>,
+    }
+
     pub trait Storage {
-        fn save(&self, buffer: &[u8], extension: String, w: u32, h: u32) -> StorageResult<String>;
+        /// Encodes an RGBA buffer and stores it under its hash.
+        fn save(
+            &self,
+            buffer: &[u8],
+            encoding: &img::Encoding,
+            w: u32,
+            h: u32,
+        ) -> StorageResult<Saved> {
+            let (name, encoded) = self.encode(buffer, encoding, w, h)?;
+            self.store(&name, &encoded)
+        }
+        /// Encodes a raw RGBA buffer and names it, without storing it yet.
+        fn encode(
+            &self,
+            buffer: &[u8],
+            encoding: &img::Encoding,
+            w: u32,
+            h: u32,
+        ) -> StorageResult<(String, Vec<u8>)> {
+            let name = format!("{}.{}", self.hash(buffer), encoding.format);
+            let encoded = img::encode(buffer, w, h, encoding).ok_or(StorageError::SaveError)?;
+            Ok((name, encoded))
+        }
+        /// Stores an already encoded image under `name`.
+        fn store(&self, name: &String, data: &[u8]) -> StorageResult<Saved>;
         fn read_to(&self, name: &String, to: &mut Vec<u8>) -> StorageResult<()>;
         fn link(&self, name: &String) -> StorageResult<String>;
+        fn delete(&self, name: &String) -> StorageResult<()>;
+        /// Name of the backend, as accepted by `backend`.
+        fn kind(&self) -> &'static str;
+        /// Naming settings the backend was built with.
+        fn naming(&self) -> &naming::Naming;
+        /// Object name for `buffer`, without extension, following the
+        /// configured naming scheme.
         fn hash(&self, buffer: &[u8]) -> String {
-            let mut hasher = DefaultHasher::new();
-            hasher.write(&buffer);
-            format!("{:x}", hasher.finish())
+            self.naming().name(buffer)
         }
     }
     #[derive(Debug)]
     pub struct Local {
         pub path: PathBuf,
+        naming: naming::Naming,
     }
 
     impl Local {
-        pub fn new() -> Self {
-            let local_path = config::local_path().unwrap();
-            let path = Path::new(&local_path);
-            return Local {
-                path: path.to_owned(),
-            };
+        pub fn new() -> StorageResult<Self> {
+            let cfg = config::config()?;
+            Ok(Local {
+                path: PathBuf::from(&cfg.local_path),
+                naming: cfg.naming(),
+            })
+        }
+
+        /// Local storage in `path`, named with the default naming settings.
+        pub fn at(path: PathBuf) -> Self {
+            Local {
+                path,
+                naming: naming::Naming::default(),
+            }
         }
     }
 
     impl Storage for Local {
-        fn save(&self, buffer: &[u8], extension: String, w: u32, h: u32) -> StorageResult<String> {
-            let hash = self.hash(buffer);
-            let filename = format!("{}.{}", hash, extension);
-            let file_path = self.path.join(Path::new(&filename));
-            let ret = file_path.as_path().display().to_string();
-            image::save_buffer(
-                file_path,
-                buffer,
-                w.into(),
-                h.into(),
-                image::ColorType::Rgba8,
-            )
-            .expect("Failure");
-            Ok(ret)
+        fn store(&self, name: &String, data: &[u8]) -> StorageResult<Saved> {
+            let file_path = self.path.join(Path::new(&name));
+            fs::write(&file_path, data)?;
+            Ok(Saved {
+                link: file_path.display().to_string(),
+                name: name.clone(),
+                delete_token: None,
+            })
         }
 
         fn read_to(&self, name: &String, to: &mut Vec<u8>) -> StorageResult<()> {
             let file_path = self.path.join(Path::new(&name));
-            fs::File::open(file_path)?.read_to_end(to)?;
+            let mut file = fs::File::open(file_path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => StorageError::NotFound,
+                _ => StorageError::IOError(e),
+            })?;
+            file.read_to_end(to)?;
             Ok(())
         }
 
@@ -266,6 +595,22 @@ pub mod storage {
             let file_path = self.path.join(Path::new(&name));
             Ok(file_path.display().to_string())
         }
+
+        fn delete(&self, name: &String) -> StorageResult<()> {
+            let file_path = self.path.join(Path::new(&name));
+            fs::remove_file(file_path).map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => StorageError::NotFound,
+                _ => StorageError::IOError(e),
+            })
+        }
+
+        fn kind(&self) -> &'static str {
+            "local"
+        }
+
+        fn naming(&self) -> &naming::Naming {
+            &self.naming
+        }
     }
 
     pub struct S3Store {
@@ -273,6 +618,7 @@ pub mod storage {
         pub endpoint: String,
         runtime: tokio::runtime::Runtime,
         client: S3Client,
+        naming: naming::Naming,
     }
     impl S3Store {
         pub fn new() -> StorageResult<Self> {
@@ -283,6 +629,7 @@ pub mod storage {
             };
             let runtime = tokio::runtime::Runtime::new()?;
             Ok(S3Store {
+                naming: sampconf.naming(),
                 bucket: sampconf.bucket,
                 endpoint: sampconf.endpoint,
                 runtime,
@@ -296,30 +643,25 @@ pub mod storage {
     }
 
     impl Storage for S3Store {
-        fn save(&self, buffer: &[u8], extension: String, w: u32, h: u32) -> StorageResult<String> {
-            let local_storage = Local {
-                path: Path::new("/tmp/").to_path_buf(),
-            };
-            let name = format!("{}.{}", self.hash(buffer), extension);
-            let link = self.link(&name);
-            let local_path = local_storage.save(&buffer, extension, w, h)?;
-            println!("{}", local_path);
-            let mut file = std::fs::File::open(&local_path)?;
-            let mut buf: Vec<u8> = vec![];
-            file.read_to_end(&mut buf)?;
-
+        fn store(&self, name: &String, data: &[u8]) -> StorageResult<Saved> {
+            let extension = Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("");
             self.runtime
                 .block_on(self.client.put_object(PutObjectRequest {
-                    body: Some(buf.into()),
+                    body: Some(data.to_vec().into()),
                     bucket: self.bucket.clone(), // I clone because of E0507, Is there any better way to do this?
-                    key: name,
+                    key: name.into(),
                     acl: Some("public-read".into()),
-                    content_type: Some("image/png".into()),
+                    content_type: Some(img::mime(extension).into()),
                     ..Default::default()
-                }))
-                .expect("Error while uploading file");
-            std::fs::remove_file(local_path)?;
-            return link;
+                }))?;
+            Ok(Saved {
+                link: self.link(name)?,
+                name: name.clone(),
+                delete_token: None,
+            })
         }
         fn read_to(&self, name: &String, to: &mut Vec<u8>) -> StorageResult<()> {
             let object = self
@@ -329,7 +671,16 @@ pub mod storage {
                     key: name.into(),
                     ..Default::default()
                 }));
-            let body = object?.body.ok_or(StorageError::ReadError)?;
+            let object = match object {
+                Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                    return Err(StorageError::NotFound)
+                }
+                Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => {
+                    return Err(StorageError::NotFound)
+                }
+                object => object?,
+            };
+            let body = object.body.ok_or(StorageError::ReadError)?;
             io::copy(&mut body.into_blocking_read(), to)?;
             Ok(())
         }
@@ -341,63 +692,719 @@ pub mod storage {
                 name
             ));
         }
+        fn delete(&self, name: &String) -> StorageResult<()> {
+            self.runtime
+                .block_on(self.client.delete_object(DeleteObjectRequest {
+                    bucket: self.bucket.clone(),
+                    key: name.into(),
+                    ..Default::default()
+                }))?;
+            Ok(())
+        }
 
-        fn hash(&self, buffer: &[u8]) -> String {
-            let mut hasher = DefaultHasher::new();
-            hasher.write(&buffer);
-            format!("{:x}", hasher.finish())
+        fn kind(&self) -> &'static str {
+            "s3"
+        }
+
+        fn naming(&self) -> &naming::Naming {
+            &self.naming
         }
     }
 
+    /// Upload protocol spoken with the server. Version 1 sent raw RGBA
+    /// buffers, version 2 sends encoded images.
+    pub const PROTOCOL_VERSION: u8 = 2;
+
     pub struct SampicServer {
         pub endpoint: String,
-        s3: S3Store,
+        token: String,
+        delete_token: Option<String>,
+        naming: naming::Naming,
     }
 
     impl SampicServer {
         pub fn new() -> StorageResult<Self> {
             let sampconf = config::config()?;
             Ok(SampicServer {
+                naming: sampconf.naming(),
                 endpoint: sampconf.sampic_endpoint,
-                s3: S3Store::new()?,
+                token: sampconf.sampic_token,
+                delete_token: None,
             })
         }
+
+        /// Token handed out by the server when the image was uploaded, needed to delete it.
+        pub fn with_delete_token(mut self, delete_token: String) -> Self {
+            self.delete_token = Some(delete_token);
+            self
+        }
+
+        /// The server serves stored images from its root, next to `/upload`.
+        fn root(&self) -> &str {
+            let endpoint = self.endpoint.trim_end_matches('/');
+            endpoint.strip_suffix("/upload").unwrap_or(endpoint)
+        }
     }
+    /// A storage backend that can be shared between threads, e.g. by the server.
+    pub type Backend = Box<dyn Storage + Send + Sync>;
+
+    /// Builds the backend named `name` ("local" or "s3") from the configuration.
+    pub fn backend(name: &str) -> StorageResult<Backend> {
+        match name.to_lowercase().as_str() {
+            "local" => Ok(Box::new(Local::new()?)),
+            "s3" => Ok(Box::new(S3Store::new()?)),
+            _ => Err(StorageError::ConfigError(
+                config::ConfigError::InvalidStorageValue,
+            )),
+        }
+    }
+
     use minreq;
     impl Storage for SampicServer {
-        fn save(&self, buffer: &[u8], extension: String, w: u32, h: u32) -> StorageResult<String> {
-            let name = format!("{}.{}", self.hash(buffer), extension);
-            let link = self.link(&name);
-            let endpoint = format!("{}?extension={}&w={}&h={}", self.endpoint, extension, w, h);
-            println!("UPLOADING to {}", endpoint);
-            minreq::post(endpoint).with_body(buffer).send()?;
-            println!("UPLOADED!");
-            link
+        /// Sends an encoded image to the server, which picks the name and
+        /// answers with the image's canonical URL.
+        fn store(&self, name: &String, data: &[u8]) -> StorageResult<Saved> {
+            let extension = Path::new(name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("");
+            let endpoint = format!(
+                "{}?v={}&extension={}",
+                self.endpoint, PROTOCOL_VERSION, extension
+            );
+            let mut request = minreq::post(endpoint).with_body(data);
+            // Without a token the server answers 401, which tells the user
+            // to set one, rather than 403 for an empty one.
+            if !self.token.is_empty() {
+                request = request.with_header("Authorization", format!("Bearer {}", self.token));
+            }
+            let response = request.send()?;
+            let error = match response.status_code {
+                200..=299 => {
+                    let uploaded: Uploaded = response.json()?;
+                    return Ok(Saved {
+                        link: uploaded.url,
+                        name: uploaded.name,
+                        delete_token: uploaded.delete_token,
+                    });
+                }
+                401 => StorageError::Unauthorized,
+                403 => StorageError::Forbidden,
+                status => StorageError::UnknownError(format!(
+                    "The server answered {}: {}",
+                    status,
+                    response.as_str()?.trim()
+                )),
+            };
+            Err(error)
         }
 
         fn read_to(&self, name: &String, to: &mut Vec<u8>) -> StorageResult<()> {
-            self.s3.read_to(name, to)
+            let response = minreq::get(self.link(name)?).send()?;
+            match response.status_code {
+                200..=299 => {
+                    to.extend_from_slice(response.as_bytes());
+                    Ok(())
+                }
+                404 => Err(StorageError::NotFound),
+                _ => Err(StorageError::ReadError),
+            }
         }
 
         fn link(&self, name: &String) -> StorageResult<String> {
-            self.s3.link(name)
+            Ok(format!("{}/{}", self.root(), name))
+        }
+
+        fn delete(&self, name: &String) -> StorageResult<()> {
+            let mut request = minreq::delete(self.link(name)?);
+            match self.delete_token.as_deref() {
+                Some("") | None => {}
+                Some(token) => request = request.with_header("X-Delete-Token", token),
+            }
+            let response = request.send()?;
+            let error = match response.status_code {
+                200..=299 => return Ok(()),
+                403 => StorageError::Forbidden,
+                404 => StorageError::NotFound,
+                status => StorageError::UnknownError(format!(
+                    "The server answered {}: {}",
+                    status,
+                    response.as_str()?.trim()
+                )),
+            };
+            Err(error)
+        }
+
+        fn kind(&self) -> &'static str {
+            "upload"
+        }
+
+        fn naming(&self) -> &naming::Naming {
+            &self.naming
         }
     }
 }
 
 pub mod img {
+    use super::config::SampConf;
+    use super::windows;
+    use image::codecs::avif::AvifEncoder;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::png::{CompressionType, FilterType, PngEncoder};
     use image::io::Reader;
+    use image::{ColorType, DynamicImage, ImageEncoder, ImageFormat, RgbaImage};
     use scrap::{Capturer, Display};
+    use std::convert::TryFrom;
+    use std::io;
+    use std::io::Cursor;
     use std::io::ErrorKind::WouldBlock;
     use std::thread;
     use std::time::Duration;
 
-    pub fn screenshot() -> (Vec<u8>, usize, usize) {
+    /// Largest width or height accepted when decoding images we didn't produce.
+    pub const MAX_DIMENSION: u32 = 16384;
+
+    /// Formats screenshots can be saved as.
+    pub const FORMATS: [&str; 4] = ["png", "jpg", "webp", "avif"];
+    pub const PNG_COMPRESSION: [&str; 3] = ["fast", "default", "best"];
+
+    /// Output format and encoder options.
+    #[derive(Clone, Debug)]
+    pub struct Encoding {
+        /// Extension of the output format.
+        pub format: String,
+        /// 1 to 100, used by JPEG, AVIF and lossy WebP.
+        pub quality: u8,
+        pub webp_lossless: bool,
+        pub png_compression: String,
+        /// Runs PNGs through oxipng, which is lossless but slow.
+        pub optimize_png: bool,
+    }
+
+    impl Encoding {
+        /// `extension` with default encoder options.
+        pub fn new(extension: &str) -> Self {
+            Encoding {
+                format: extension.to_string(),
+                quality: 85,
+                webp_lossless: false,
+                png_compression: "default".into(),
+                optimize_png: false,
+            }
+        }
+
+        pub fn from_config(cfg: &SampConf) -> Self {
+            Encoding {
+                format: cfg.format.clone(),
+                quality: cfg.quality.max(1).min(100) as u8,
+                webp_lossless: cfg.webp_lossless,
+                png_compression: cfg.png_compression.clone(),
+                optimize_png: cfg.optimize_png,
+            }
+        }
+    }
+
+    /// Encodes an RGBA buffer as described by `encoding`.
+    pub fn encode(buffer: &[u8], w: u32, h: u32, encoding: &Encoding) -> Option<Vec<u8>> {
+        let mut encoded = Vec::new();
+        match encoding.format.as_str() {
+            "png" => {
+                let compression = match encoding.png_compression.as_str() {
+                    "fast" => CompressionType::Fast,
+                    "best" => CompressionType::Best,
+                    _ => CompressionType::Default,
+                };
+                PngEncoder::new_with_quality(&mut encoded, compression, FilterType::Adaptive)
+                    .write_image(buffer, w, h, ColorType::Rgba8)
+                    .ok()?;
+                if encoding.optimize_png {
+                    let options = oxipng::Options::from_preset(2);
+                    encoded = oxipng::optimize_from_memory(&encoded, &options).ok()?;
+                }
+            }
+            "jpg" | "jpeg" => {
+                // JPEG has no alpha channel, and screenshots are opaque anyway.
+                let image = RgbaImage::from_raw(w, h, buffer.to_vec())?;
+                let rgb = DynamicImage::ImageRgba8(image).to_rgb8();
+                JpegEncoder::new_with_quality(&mut encoded, encoding.quality)
+                    .write_image(&rgb, w, h, ColorType::Rgb8)
+                    .ok()?;
+            }
+            "webp" => {
+                let encoder = webp::Encoder::from_rgba(buffer, w, h);
+                let memory = if encoding.webp_lossless {
+                    encoder.encode_lossless()
+                } else {
+                    encoder.encode(f32::from(encoding.quality))
+                };
+                encoded.extend_from_slice(&memory);
+            }
+            "avif" => {
+                AvifEncoder::new_with_speed_quality(&mut encoded, 6, encoding.quality)
+                    .write_image(buffer, w, h, ColorType::Rgba8)
+                    .ok()?;
+            }
+            extension => {
+                let format = ImageFormat::from_extension(extension)?;
+                let image = RgbaImage::from_raw(w, h, buffer.to_vec())?;
+                DynamicImage::ImageRgba8(image)
+                    .write_to(&mut encoded, format)
+                    .ok()?;
+            }
+        }
+        Some(encoded)
+    }
+
+    fn be32(bytes: &[u8], at: usize) -> Option<u32> {
+        let bytes = bytes.get(at..at + 4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// The ISOBMFF boxes `data` is made of, as their type and contents.
+    /// `None` unless they cover it exactly.
+    fn boxes(data: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+        let mut boxes = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (header, size) = match be32(rest, 0)? {
+                // A 64 bit size follows the type.
+                1 => {
+                    let size = (u64::from(be32(rest, 8)?) << 32) | u64::from(be32(rest, 12)?);
+                    (16, usize::try_from(size).ok()?)
+                }
+                // The last box, up to the end of the file.
+                0 => (8, rest.len()),
+                size => (8, size as usize),
+            };
+            if size < header || size > rest.len() {
+                return None;
+            }
+            boxes.push((&rest[4..8], &rest[header..size]));
+            rest = &rest[size..];
+        }
+        Some(boxes)
+    }
+
+    /// Contents of the first box of type `kind`.
+    fn child<'a>(boxes: &[(&[u8], &'a [u8])], kind: &[u8]) -> Option<&'a [u8]> {
+        boxes
+            .iter()
+            .find(|(found, _)| *found == kind)
+            .map(|(_, contents)| *contents)
+    }
+
+    /// Width and height of an AVIF image, read from its `ispe` property since
+    /// `image` can encode AVIF but not decode it. The boxes on the way there
+    /// are checked, which is as far as an image that can't be decoded can
+    /// be validated.
+    pub fn avif_dimensions(data: &[u8]) -> Option<(u32, u32)> {
+        let top = boxes(data)?;
+        // Major brand, minor version, then compatible brands.
+        let ftyp = child(&top, b"ftyp")?;
+        if top[0].0 != b"ftyp" || ftyp.len() < 8 || ftyp.len() % 4 != 0 {
+            return None;
+        }
+        let mut brands = ftyp[..4].chunks(4).chain(ftyp[8..].chunks(4));
+        if !brands.any(|brand| brand == b"avif") {
+            return None;
+        }
+        child(&top, b"mdat")?;
+        // `meta` and `ispe` are full boxes, their contents start with a
+        // version and flags word.
+        let meta = boxes(child(&top, b"meta")?.get(4..)?)?;
+        // Version and flags, then a predefined word and the handler type.
+        if child(&meta, b"hdlr")?.get(8..12)? != b"pict" {
+            return None;
+        }
+        let iprp = boxes(child(&meta, b"iprp")?)?;
+        let ipco = boxes(child(&iprp, b"ipco")?)?;
+        child(&iprp, b"ipma")?;
+        let ispe = child(&ipco, b"ispe")?;
+        if ispe.len() != 12 {
+            return None;
+        }
+        let (w, h) = (be32(ispe, 4)?, be32(ispe, 8)?);
+        if w == 0 || h == 0 {
+            return None;
+        }
+        Some((w, h))
+    }
+
+    /// What decoding every frame of a GIF, PNG or WebP found.
+    #[derive(Debug, PartialEq)]
+    pub enum Frames {
+        /// A single image, or a format without animations, left to `decode`.
+        Still,
+        /// More than one frame, all of which decoded, on a canvas this size.
+        Animated(u32, u32),
+        /// Not in the format `extension` claims, too large, or with a broken
+        /// frame.
+        Invalid,
+    }
+
+    /// Decodes every frame of an image in the format `extension` claims.
+    /// Only the first frame of an animation would be decoded by `decode`, so
+    /// they're checked here instead.
+    pub fn frames(data: &[u8], extension: &str) -> Frames {
+        let format = match ImageFormat::from_extension(extension) {
+            Some(format @ ImageFormat::Gif)
+            | Some(format @ ImageFormat::Png)
+            | Some(format @ ImageFormat::WebP) => format,
+            _ => return Frames::Still,
+        };
+        if image::guess_format(data).ok() != Some(format) {
+            return Frames::Invalid;
+        }
+        let frames = match format {
+            ImageFormat::Gif => gif_frames(data),
+            ImageFormat::Png => png_frames(data),
+            _ => webp_frames(data),
+        };
+        match frames {
+            Some((w, h, _)) if w > MAX_DIMENSION || h > MAX_DIMENSION => Frames::Invalid,
+            Some((w, h, count)) if count > 1 => Frames::Animated(w, h),
+            Some(_) => Frames::Still,
+            None => Frames::Invalid,
+        }
+    }
+
+    /// Canvas size and frame count of a GIF, looping or not.
+    fn gif_frames(data: &[u8]) -> Option<(u32, u32, usize)> {
+        let mut options = gif::DecodeOptions::new();
+        // Frames are only checked, there's no need to expand their palettes.
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(Cursor::new(data)).ok()?;
+        let (w, h) = (u32::from(decoder.width()), u32::from(decoder.height()));
+        if w > MAX_DIMENSION || h > MAX_DIMENSION {
+            return Some((w, h, 0));
+        }
+        let mut count = 0;
+        // Each frame has its own size, which is checked before allocating
+        // anything for it.
+        while let Some(frame) = decoder.next_frame_info().ok()? {
+            if u32::from(frame.width) > MAX_DIMENSION || u32::from(frame.height) > MAX_DIMENSION {
+                return None;
+            }
+            let mut buffer = vec![0; decoder.buffer_size()];
+            decoder.read_into_buffer(&mut buffer).ok()?;
+            count += 1;
+        }
+        Some((w, h, count))
+    }
+
+    /// Canvas size and frame count of an APNG. Plain PNGs are reported as a
+    /// single frame without being decoded.
+    fn png_frames(data: &[u8]) -> Option<(u32, u32, usize)> {
+        let mut reader = png::Decoder::new(Cursor::new(data)).read_info().ok()?;
+        let info = reader.info();
+        let (w, h) = (info.width, info.height);
+        let count = match &info.animation_control {
+            Some(control) => control.num_frames as usize,
+            None => return Some((w, h, 1)),
+        };
+        // The default image is decoded first, whether it's the first frame of
+        // the animation or not.
+        let images = count + info.frame_control.is_none() as usize;
+        if w > MAX_DIMENSION || h > MAX_DIMENSION {
+            return Some((w, h, count));
+        }
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        for _ in 0..images {
+            reader.next_frame(&mut buffer).ok()?;
+        }
+        Some((w, h, count))
+    }
+
+    /// Canvas size and frame count of an animated WebP. Still ones are
+    /// reported as a single frame without being decoded.
+    fn webp_frames(data: &[u8]) -> Option<(u32, u32, usize)> {
+        let animated = data.get(12..16)? == b"VP8X" && data.get(20)? & 0x02 != 0;
+        if !animated {
+            return Some((0, 0, 1));
+        }
+        // RIFF chunks: a fourcc, a little endian size and the payload, padded
+        // to an even length. They must cover the file exactly.
+        let word = |at: usize| -> Option<usize> {
+            let bytes = data.get(at..at + 4)?;
+            Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+        };
+        if word(4)?.checked_add(8)? != data.len() {
+            return None;
+        }
+        let mut at = 12;
+        let mut count = 0;
+        while at < data.len() {
+            if data.get(at..at + 4)? == b"ANMF" {
+                count += 1;
+            }
+            let size = word(at + 4)?;
+            at = at.checked_add(8 + size + size % 2)?;
+        }
+        if at != data.len() {
+            return None;
+        }
+        let decoder = webp_animation::Decoder::new(data).ok()?;
+        let (w, h) = decoder.dimensions();
+        if w > MAX_DIMENSION || h > MAX_DIMENSION {
+            return Some((w, h, count));
+        }
+        // The iterator ends at the first frame that fails to decode.
+        if decoder.into_iter().count() != count {
+            return None;
+        }
+        Some((w, h, count))
+    }
+
+    /// Decodes an encoded image into an RGBA buffer, making sure it really is
+    /// in the format `extension` claims.
+    pub fn decode(data: &[u8], extension: &str) -> Option<(Vec<u8>, u32, u32)> {
+        let format = ImageFormat::from_extension(extension)?;
+        if image::guess_format(data).ok()? != format {
+            return None;
+        }
+        let (w, h) = Reader::with_format(Cursor::new(data), format)
+            .into_dimensions()
+            .ok()?;
+        if w > MAX_DIMENSION || h > MAX_DIMENSION {
+            return None;
+        }
+        let image = image::load_from_memory_with_format(data, format)
+            .ok()?
+            .to_rgba8();
+        Some((image.into_raw(), w, h))
+    }
+
+    /// An encoded image that was checked to be in the format it claims.
+    pub enum Checked {
+        /// A still image and its RGBA pixels, which it's named after.
+        Pixels(Vec<u8>, u32, u32),
+        /// An animation, which can't be decoded into a single buffer and is
+        /// named after its bytes instead.
+        Animated(u32, u32),
+        /// An AVIF image, which can't be decoded at all and is also named
+        /// after its bytes.
+        Avif(u32, u32),
+    }
+
+    /// Checks that `data` is a valid image in the format `extension` claims,
+    /// the same way for uploads and pushed files.
+    pub fn check(data: &[u8], extension: &str) -> Option<Checked> {
+        match frames(data, extension) {
+            Frames::Invalid => None,
+            Frames::Animated(w, h) => Some(Checked::Animated(w, h)),
+            // Can't be decoded, so the header is all there is to check.
+            Frames::Still if extension == "avif" => match avif_dimensions(data)? {
+                (w, h) if w > MAX_DIMENSION || h > MAX_DIMENSION => None,
+                (w, h) => Some(Checked::Avif(w, h)),
+            },
+            Frames::Still => {
+                let (pixels, w, h) = decode(data, extension)?;
+                Some(Checked::Pixels(pixels, w, h))
+            }
+        }
+    }
+
+    /// Extension of the format an encoded image is actually in.
+    pub fn extension(data: &[u8]) -> Option<&'static str> {
+        match image::guess_format(data).ok()? {
+            ImageFormat::Png => Some("png"),
+            ImageFormat::Jpeg => Some("jpg"),
+            ImageFormat::Gif => Some("gif"),
+            ImageFormat::WebP => Some("webp"),
+            ImageFormat::Bmp => Some("bmp"),
+            ImageFormat::Avif => Some("avif"),
+            _ => None,
+        }
+    }
+
+    pub fn mime(extension: &str) -> &'static str {
+        match extension {
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "avif" => "image/avif",
+            "bmp" => "image/bmp",
+            _ => "application/octet-stream",
+        }
+    }
+
+    /// Which displays a screenshot covers.
+    #[derive(Clone, Copy, Debug)]
+    pub enum Displays {
+        Primary,
+        /// Position in the order RandR lists monitors on X11, or the order
+        /// `scrap::Display::all` lists displays elsewhere.
+        Index(usize),
+        All,
+    }
+
+    /// Captures the chosen displays. With `Displays::All` every display is
+    /// placed where it is on the desktop.
+    pub fn screenshot(displays: Displays) -> io::Result<(Vec<u8>, usize, usize)> {
+        #[cfg(not(target_os = "linux"))]
+        {
+            if let Displays::All = displays {
+                let frames = Display::all()?
+                    .into_iter()
+                    .map(capture)
+                    .collect::<io::Result<Vec<(Vec<u8>, usize, usize)>>>()?;
+                let sizes: Vec<(usize, usize)> = frames.iter().map(|(_, w, h)| (*w, *h)).collect();
+                return Ok(stitch(&frames, &layout(&sizes)));
+            }
+        }
+        let (display, area) = source(displays)?;
+        let (buffer, w, h) = capture(display)?;
+        match area {
+            Some(area) => cut(&buffer, w, h, area).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "The display is off screen")
+            }),
+            None => Ok((buffer, w, h)),
+        }
+    }
+
+    /// The scrap display a capture of `displays` is taken from, and the part
+    /// of it they cover if it's not all of it.
+    ///
+    /// On X11 scrap captures X screens, that is the whole root window, and
+    /// monitors are the parts of it RandR says they are.
+    #[cfg(target_os = "linux")]
+    pub fn source(displays: Displays) -> io::Result<(Display, Option<[f64; 4]>)> {
+        let monitors = windows::monitors().unwrap_or_default();
+        let covered = area(displays, &monitors);
+        if let (Displays::Index(_), None) = (displays, covered) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No display with that index",
+            ));
+        }
+        Ok((Display::primary()?, covered))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn source(displays: Displays) -> io::Result<(Display, Option<[f64; 4]>)> {
+        let display = match displays {
+            Displays::Index(index) => Display::all()?.into_iter().nth(index).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "No display with that index")
+            })?,
+            _ => Display::primary()?,
+        };
+        Ok((display, None))
+    }
+
+    /// `[x, y, w, h]`, relative to the root window, of what `displays` cover
+    /// on `monitors`. `None` if there's no such monitor.
+    pub(crate) fn area(displays: Displays, monitors: &[windows::Monitor]) -> Option<[f64; 4]> {
+        match displays {
+            Displays::Primary => Some(
+                monitors
+                    .iter()
+                    .find(|monitor| monitor.primary)
+                    .or_else(|| monitors.first())?
+                    .rect,
+            ),
+            Displays::Index(index) => Some(monitors.get(index)?.rect),
+            Displays::All => {
+                if monitors.is_empty() {
+                    return None;
+                }
+                let rects = || monitors.iter().map(|monitor| monitor.rect);
+                let left = rects().map(|rect| rect[0]).fold(f64::MAX, f64::min);
+                let top = rects().map(|rect| rect[1]).fold(f64::MAX, f64::min);
+                let right = rects()
+                    .map(|rect| rect[0] + rect[2])
+                    .fold(f64::MIN, f64::max);
+                let bottom = rects()
+                    .map(|rect| rect[1] + rect[3])
+                    .fold(f64::MIN, f64::max);
+                Some([left, top, right - left, bottom - top])
+            }
+        }
+    }
+
+    /// Copies `area` out of a `w`x`h` RGBA buffer, `None` if it's outside.
+    pub(crate) fn cut(
+        buffer: &[u8],
+        w: usize,
+        h: usize,
+        area: [f64; 4],
+    ) -> Option<(Vec<u8>, usize, usize)> {
+        let [x, y, area_w, area_h] = super::region::clip(area, [w as f64, h as f64])?;
+        let [x, y, area_w, area_h] = [x as usize, y as usize, area_w as usize, area_h as usize];
+        let mut pixels = Vec::with_capacity(area_w * area_h * 4);
+        for row in y..y + area_h {
+            pixels.extend_from_slice(&buffer[(row * w + x) * 4..(row * w + x + area_w) * 4]);
+        }
+        Some((pixels, area_w, area_h))
+    }
+
+    /// Top left corner, relative to the root window, of what a screenshot of
+    /// `displays` covers. `None` if the monitors couldn't be listed.
+    pub fn origin(displays: Displays) -> Option<[f64; 2]> {
+        let monitors = windows::monitors().ok()?;
+        match (area(displays, &monitors), displays) {
+            (Some(area), _) => Some([area[0], area[1]]),
+            (None, Displays::Index(_)) => None,
+            // The whole root window is captured.
+            (None, _) => Some([0.0, 0.0]),
+        }
+    }
+
+    /// Where frames of `sizes` go on the stitched canvas: left to right, top
+    /// aligned, in the order they're enumerated.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn layout(sizes: &[(usize, usize)]) -> Vec<[usize; 2]> {
+        let mut left = 0;
+        sizes
+            .iter()
+            .map(|(w, _)| {
+                let position = [left, 0];
+                left += w;
+                position
+            })
+            .collect()
+    }
+
+    /// Number of connected displays.
+    #[cfg(target_os = "linux")]
+    pub fn display_count() -> usize {
+        windows::monitors()
+            .map(|monitors| monitors.len())
+            .unwrap_or(1)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn display_count() -> usize {
+        Display::all().map(|displays| displays.len()).unwrap_or(0)
+    }
+
+    /// Places RGBA frames at their `[x, y]` positions on an opaque black
+    /// canvas just large enough for all of them. Gaps between monitors stay
+    /// black.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn stitch(
+        frames: &[(Vec<u8>, usize, usize)],
+        positions: &[[usize; 2]],
+    ) -> (Vec<u8>, usize, usize) {
+        let placed = || frames.iter().zip(positions);
+        let w = placed().map(|((_, w, _), [x, _])| x + w).max().unwrap_or(0);
+        let h = placed().map(|((_, _, h), [_, y])| y + h).max().unwrap_or(0);
+        let mut canvas = [0, 0, 0, 255].repeat(w * h);
+        for ((frame, frame_w, frame_h), [left, top]) in placed() {
+            for y in 0..*frame_h {
+                let row = &frame[y * frame_w * 4..(y + 1) * frame_w * 4];
+                let start = ((top + y) * w + left) * 4;
+                canvas[start..start + row.len()].copy_from_slice(row);
+            }
+        }
+        (canvas, w, h)
+    }
+
+    fn capture(display: Display) -> io::Result<(Vec<u8>, usize, usize)> {
         let one_second = Duration::new(1, 0);
         let one_frame = one_second / 60;
 
-        let display = Display::primary().expect("Couldn't find primary display.");
-        let mut capturer = Capturer::new(display).expect("Couldn't begin capture.");
+        let mut capturer = Capturer::new(display)?;
         let (w, h) = (capturer.width(), capturer.height());
 
         loop {
@@ -411,7 +1418,7 @@ pub mod img {
                         thread::sleep(one_frame);
                         continue;
                     } else {
-                        panic!("Error: {}", error);
+                        return Err(error);
                     }
                 }
             };
@@ -424,7 +1431,7 @@ pub mod img {
                     bitflipped.extend_from_slice(&[buffer[i + 2], buffer[i + 1], buffer[i], 255]);
                 }
             }
-            return (bitflipped, w, h);
+            return Ok((bitflipped, w, h));
         }
     }
 
@@ -447,27 +1454,489 @@ pub mod img {
 }
 extern crate scrap;
 
+use error::{Class, Context, Error};
 use img::screenshot;
 use std::convert::TryFrom;
+use std::io::Read;
+use std::time::{Duration, Instant};
 use storage::Storage;
 extern crate arboard;
-use arboard::Clipboard;
+use arboard::{Clipboard, ImageData};
+use std::borrow::Cow;
 
 pub mod server {
-    use super::storage::{S3Store, Storage, StorageError};
-    use rocket::Data;
-    use std::io::Read;
+    use super::config::{ConfigError, SampConf};
+    use super::img;
+    use super::keys::{KeyStore, Verdict};
+    use super::limits::{Client, Exceeded, LimitError, Limiter};
+    use super::storage::{self, Backend, Storage, StorageError};
+    use super::uploads::Uploads;
+    use rocket::http::{ContentType, Status};
+    use rocket::request::{self, FromRequest, Request};
+    use rocket::response::{self, content, Responder, Response};
+    use rocket::{Data, Outcome, Rocket, State};
+    use serde::{Deserialize, Serialize};
+    use std::io::{self, Cursor, Read};
+    use std::net::IpAddr;
+    use std::path::Path;
+    use subtle::ConstantTimeEq;
     const LIMIT: u64 = 50000000000;
+    // Object names are content hashes or random, so a name never changes its contents.
+    const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+    /// The server's answer to a successful upload.
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct Uploaded {
+        pub url: String,
+        pub name: String,
+        /// Only handed to whoever uploaded the image first, so uploading the
+        /// same image again doesn't let anyone else delete it.
+        #[serde(default)]
+        pub delete_token: Option<String>,
+        pub width: u32,
+        pub height: u32,
+        pub size: u64,
+        pub content_type: String,
+    }
+
+    /// An upload that was validated and encoded, ready to be stored.
+    struct Prepared {
+        name: String,
+        encoded: Vec<u8>,
+        width: u32,
+        height: u32,
+    }
+
+    /// Where the server keeps uploads. Built once at launch and shared by every request.
+    pub struct Store {
+        backend: Backend,
+        public_url: Option<String>,
+        uploads: Uploads,
+    }
+
+    impl Store {
+        /// `public_url` is where this server is reachable; when set, links point
+        /// to its `GET /<name>` route instead of straight to the backend.
+        pub fn new(backend: Backend, public_url: Option<String>, uploads: Uploads) -> Self {
+            Store {
+                backend,
+                public_url,
+                uploads,
+            }
+        }
+
+        pub fn from_config(cfg: &SampConf) -> Result<Self, StorageError> {
+            let public_url = match cfg.public_url.as_str() {
+                "" => None,
+                url => Some(url.trim_end_matches('/').to_string()),
+            };
+            let backend = storage::backend(&cfg.storage)?;
+            // Local links are paths on the server, clients can't open them.
+            if backend.kind() == "local" && public_url.is_none() {
+                return Err(StorageError::ConfigError(ConfigError::PublicURLNotDefined));
+            }
+            let uploads = Uploads::open()
+                .map_err(|e| StorageError::UnknownError(format!("Upload records: {}", e)))?;
+            Ok(Store::new(backend, public_url, uploads))
+        }
+
+        /// The answer to an upload of an image that is already stored, which
+        /// is neither stored nor charged again.
+        fn existing(&self, prepared: &Prepared) -> Result<Option<Uploaded>, StorageError> {
+            let upload = match self.uploads.find(&prepared.name) {
+                Some(upload) => upload,
+                None => return Ok(None),
+            };
+            let link = self.backend.link(&prepared.name)?;
+            let mut uploaded = self.uploaded(prepared, link, None);
+            uploaded.size = upload.size;
+            Ok(Some(uploaded))
+        }
+
+        /// Stores an upload. A `None` deletion token means the same image was
+        /// recorded by another upload in the meantime, and this one shouldn't
+        /// be charged.
+        fn store(
+            &self,
+            prepared: Prepared,
+            key: &str,
+            ip: Option<IpAddr>,
+        ) -> Result<Uploaded, StorageError> {
+            let saved = self.backend.store(&prepared.name, &prepared.encoded)?;
+            let size = prepared.encoded.len() as u64;
+            let delete_token = self
+                .uploads
+                .record(&prepared.name, key, ip.map(|ip| ip.to_string()), size)
+                .map_err(|e| StorageError::UnknownError(format!("Upload records: {}", e)))?;
+            Ok(self.uploaded(&prepared, saved.link, delete_token))
+        }
+
+        fn uploaded(
+            &self,
+            prepared: &Prepared,
+            link: String,
+            delete_token: Option<String>,
+        ) -> Uploaded {
+            let url = match &self.public_url {
+                Some(url) => format!("{}/{}", url, prepared.name),
+                None => link,
+            };
+            let extension = Path::new(&prepared.name)
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .unwrap_or("");
+            Uploaded {
+                content_type: img::mime(extension).to_string(),
+                url,
+                name: prepared.name.clone(),
+                delete_token,
+                width: prepared.width,
+                height: prepared.height,
+                size: prepared.encoded.len() as u64,
+            }
+        }
+    }
+
+    /// An image read back from storage, ready to be sent to the client.
+    pub struct StoredImage {
+        name: String,
+        buffer: Vec<u8>,
+    }
+
+    impl<'r> Responder<'r> for StoredImage {
+        fn respond_to(self, _: &Request) -> response::Result<'r> {
+            let path = Path::new(&self.name);
+            let content_type = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .and_then(|extension| ContentType::parse_flexible(img::mime(extension)))
+                .unwrap_or(ContentType::Binary);
+            let hash = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or(&self.name);
+            let etag = format!("\"{}\"", hash);
+            Response::build()
+                .header(content_type)
+                .raw_header("ETag", etag)
+                .raw_header("Cache-Control", CACHE_CONTROL)
+                .sized_body(Cursor::new(self.buffer))
+                .ok()
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum AuthError {
+        Missing,
+        Invalid,
+        Revoked,
+        Unavailable,
+    }
+
+    /// Request guard for routes that need a valid `Authorization: Bearer <token>` header.
+    pub struct ApiKey {
+        pub name: String,
+    }
+
+    impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+        type Error = AuthError;
+
+        fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+            let token = request
+                .headers()
+                .get_one("Authorization")
+                .and_then(|header| header.strip_prefix("Bearer "))
+                .map(str::trim)
+                .filter(|token| !token.is_empty());
+            let token = match token {
+                Some(token) => token,
+                None => return Outcome::Failure((Status::Unauthorized, AuthError::Missing)),
+            };
+            let keys = request.guard::<State<KeyStore>>()?;
+            match keys.verify(token) {
+                Ok(Verdict::Valid(name)) => Outcome::Success(ApiKey { name }),
+                Ok(Verdict::Revoked) => Outcome::Failure((Status::Forbidden, AuthError::Revoked)),
+                Ok(Verdict::Unknown) => Outcome::Failure((Status::Forbidden, AuthError::Invalid)),
+                Err(_) => Outcome::Failure((Status::InternalServerError, AuthError::Unavailable)),
+            }
+        }
+    }
+
+    /// The `X-Delete-Token` header, kept out of the URL so it doesn't end up in
+    /// proxy and server logs.
+    pub struct DeleteToken(pub String);
+
+    impl<'a, 'r> FromRequest<'a, 'r> for DeleteToken {
+        type Error = ();
+
+        fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+            match request.headers().get_one("X-Delete-Token") {
+                Some(token) => Outcome::Success(DeleteToken(token.trim().to_string())),
+                None => Outcome::Forward(()),
+            }
+        }
+    }
+
+    /// The client's address. `X-Real-IP` is only honored with `trust_proxy`,
+    /// anyone could send it otherwise.
+    pub struct Peer(pub Option<IpAddr>);
+
+    impl<'a, 'r> FromRequest<'a, 'r> for Peer {
+        type Error = ();
+
+        fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+            let limiter = request.guard::<State<Limiter>>()?;
+            let ip = if limiter.trusts_proxy() {
+                request.client_ip()
+            } else {
+                request.remote().map(|remote| remote.ip())
+            };
+            Outcome::Success(Peer(ip))
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum ServerError {
+        Storage(StorageError),
+        RateLimited(Exceeded),
+        BadRequest(&'static str),
+        Denied(&'static str),
+    }
+
+    impl From<StorageError> for ServerError {
+        fn from(e: StorageError) -> ServerError {
+            ServerError::Storage(e)
+        }
+    }
+
+    impl From<io::Error> for ServerError {
+        fn from(e: io::Error) -> ServerError {
+            ServerError::Storage(e.into())
+        }
+    }
+
+    impl From<LimitError> for ServerError {
+        fn from(e: LimitError) -> ServerError {
+            match e {
+                LimitError::Exceeded(exceeded) => ServerError::RateLimited(exceeded),
+                e => ServerError::Storage(StorageError::UnknownError(format!(
+                    "Usage counters: {}",
+                    e
+                ))),
+            }
+        }
+    }
+
+    impl<'r> Responder<'r> for ServerError {
+        fn respond_to(self, _: &Request) -> response::Result<'r> {
+            match self {
+                ServerError::RateLimited(exceeded) => Response::build()
+                    .status(Status::TooManyRequests)
+                    .raw_header("Retry-After", exceeded.retry_after.to_string())
+                    .sized_body(Cursor::new(format!("{}.\n", exceeded)))
+                    .ok(),
+                ServerError::BadRequest(reason) => Response::build()
+                    .status(Status::BadRequest)
+                    .sized_body(Cursor::new(format!("{}.\n", reason)))
+                    .ok(),
+                ServerError::Denied(reason) => Response::build()
+                    .status(Status::Forbidden)
+                    .sized_body(Cursor::new(format!("{}.\n", reason)))
+                    .ok(),
+                ServerError::Storage(e) => {
+                    eprintln!("Storage error: {}", e);
+                    Err(Status::InternalServerError)
+                }
+            }
+        }
+    }
+
+    #[rocket::catch(401)]
+    pub fn unauthorized() -> &'static str {
+        "Missing API key: send it as an `Authorization: Bearer <token>` header.\n"
+    }
+
+    #[rocket::catch(403)]
+    pub fn forbidden() -> &'static str {
+        "Invalid or revoked API key: ask the server admin for a new one.\n"
+    }
+
+    /// Protocol version 1: the body is a raw `w`x`h` RGBA buffer.
+    fn from_raw(
+        store: &Store,
+        buffer: &[u8],
+        extension: &str,
+        w: Option<u32>,
+        h: Option<u32>,
+    ) -> Result<Prepared, ServerError> {
+        let (w, h) = match (w, h) {
+            (Some(w), Some(h)) => (w, h),
+            _ => return Err(ServerError::BadRequest("Raw uploads need `w` and `h`")),
+        };
+        if buffer.len() as u64 != u64::from(w) * u64::from(h) * 4 {
+            return Err(ServerError::BadRequest(
+                "Body size doesn't match a `w`x`h` RGBA buffer",
+            ));
+        }
+        let encoded = img::encode(buffer, w, h, &img::Encoding::new(extension))
+            .ok_or(ServerError::BadRequest("Unsupported extension"))?;
+        Ok(Prepared {
+            name: format!("{}.{}", store.backend.hash(buffer), extension),
+            encoded,
+            width: w,
+            height: h,
+        })
+    }
+
+    /// Protocol version 2: the body is an image already encoded as `extension`.
+    fn from_encoded(
+        store: &Store,
+        buffer: Vec<u8>,
+        extension: &str,
+    ) -> Result<Prepared, ServerError> {
+        const INVALID: ServerError =
+            ServerError::BadRequest("Body isn't a valid image of the given extension");
+        let (hash, width, height) = match img::check(&buffer, extension).ok_or(INVALID)? {
+            // Named after its pixels rather than its bytes, like raw uploads.
+            img::Checked::Pixels(pixels, width, height) => {
+                (store.backend.hash(&pixels), width, height)
+            }
+            img::Checked::Animated(width, height) | img::Checked::Avif(width, height) => {
+                (store.backend.hash(&buffer), width, height)
+            }
+        };
+        Ok(Prepared {
+            name: format!("{}.{}", hash, extension),
+            encoded: buffer,
+            width,
+            height,
+        })
+    }
+
+    #[rocket::post("/upload?<v>&<extension>&<w>&<h>", data = "<data>")]
+    pub fn upload(
+        key: ApiKey,
+        peer: Peer,
+        limiter: State<Limiter>,
+        store: State<Store>,
+        v: Option<u8>,
+        extension: String,
+        w: Option<u32>,
+        h: Option<u32>,
+        data: Data,
+    ) -> Result<content::Json<String>, ServerError> {
+        let mut clients = vec![Client::Key(&key.name)];
+        if let Some(ip) = peer.0 {
+            clients.push(Client::Ip(ip));
+        }
+        limiter.request(&clients)?;
+        // One byte past what the clients may still send today is enough to
+        // tell the upload is over quota, there's no need to read the rest.
+        let remaining = limiter.remaining(&clients);
+        let mut buffer = Vec::new();
+        data.open()
+            .take(remaining.saturating_add(1).min(LIMIT))
+            .read_to_end(&mut buffer)?;
+        // Charged whatever happens next, invalid and repeated uploads included.
+        limiter.receive(&clients, buffer.len() as u64)?;
+        // Old clients don't send a version and upload raw RGBA buffers.
+        let prepared = match v.unwrap_or(1) {
+            1 => from_raw(&store, &buffer, &extension, w, h)?,
+            2 => from_encoded(&store, buffer, &extension)?,
+            _ => return Err(ServerError::BadRequest("Unsupported protocol version")),
+        };
+        let uploaded = match store.existing(&prepared)? {
+            Some(uploaded) => uploaded,
+            None => {
+                // Charged to the storage quota what is stored, which is what
+                // deleting it frees.
+                let size = prepared.encoded.len() as u64;
+                limiter.reserve(&clients, size)?;
+                let stored = store.store(prepared, &key.name, peer.0);
+                match &stored {
+                    Ok(uploaded) if uploaded.delete_token.is_some() => {}
+                    _ => {
+                        if let Err(e) = limiter.free(&clients, size) {
+                            eprintln!("Couldn't release {} bytes of quota: {}", size, e);
+                        }
+                    }
+                }
+                stored?
+            }
+        };
+        let body = serde_json::to_string(&uploaded)
+            .map_err(|e| StorageError::UnknownError(e.to_string()))?;
+        Ok(content::Json(body))
+    }
 
-    #[rocket::post("/upload?<extension>&<w>&<h>", data = "<data>")]
-    pub fn upload(extension: String, w: u32, h: u32, data: Data) -> Result<String, StorageError> {
+    #[rocket::get("/<name>")]
+    pub fn fetch(store: State<Store>, name: String) -> Result<Option<StoredImage>, StorageError> {
+        // Names are flat object keys; anything that could walk out of the
+        // storage directory simply doesn't exist.
+        if name.starts_with('.') || name.contains('/') || name.contains('\\') {
+            return Ok(None);
+        }
         let mut buffer = Vec::new();
-        data.open().take(LIMIT).read_to_end(&mut buffer)?;
-        S3Store::new()?.save(&buffer, extension.into(), w, h)
+        match store.backend.read_to(&name, &mut buffer) {
+            Ok(()) => Ok(Some(StoredImage { name, buffer })),
+            Err(StorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[rocket::delete("/<name>")]
+    pub fn delete(
+        store: State<Store>,
+        limiter: State<Limiter>,
+        name: String,
+        token: Option<DeleteToken>,
+    ) -> Result<Option<Status>, ServerError> {
+        let upload = match store.uploads.find(&name) {
+            Some(upload) => upload,
+            None => return Ok(None),
+        };
+        // Compared in constant time, like API keys.
+        let matches =
+            |token: &str| bool::from(token.as_bytes().ct_eq(upload.delete_token.as_bytes()));
+        match token {
+            Some(DeleteToken(token)) if matches(&token) => {}
+            Some(_) => return Err(ServerError::Denied("Wrong deletion token")),
+            None => {
+                return Err(ServerError::Denied(
+                    "Missing deletion token: send it as an `X-Delete-Token` header",
+                ))
+            }
+        }
+        match store.backend.delete(&name) {
+            Ok(()) | Err(StorageError::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+        store
+            .uploads
+            .forget(&name)
+            .map_err(|e| StorageError::UnknownError(format!("Upload records: {}", e)))?;
+        let mut clients = vec![Client::Key(&upload.key)];
+        if let Some(ip) = upload.ip.as_ref().and_then(|ip| ip.parse().ok()) {
+            clients.push(Client::Ip(ip));
+        }
+        // The upload is gone either way, so this is only worth logging.
+        if let Err(e) = limiter.free(&clients, upload.size) {
+            eprintln!("Couldn't free {} bytes of quota: {}", upload.size, e);
+        }
+        Ok(Some(Status::NoContent))
+    }
+
+    pub fn rocket(keys: KeyStore, limiter: Limiter, store: Store) -> Rocket {
+        rocket::ignite()
+            .mount("/", rocket::routes![upload, fetch, delete])
+            .register(rocket::catchers![unauthorized, forbidden])
+            .manage(keys)
+            .manage(limiter)
+            .manage(store)
     }
 }
 
-use notify_rust::{Hint, Notification};
+use notify_rust::{Hint, Notification, Timeout};
 fn notify(path: &str, message: &str) {
     let mut notif = Notification::new();
     notif
@@ -479,57 +1948,656 @@ fn notify(path: &str, message: &str) {
         .sound_name("message-new-instant");
     #[cfg(target_os = "linux")]
     notif.hint(Hint::Transient(true));
-    notif.show().expect("Notification Failure!");
-}
-
-pub fn sampic_screenshot<T: 'static + Storage + std::marker::Send>(storage: T) -> String {
-    const EXTENSION: &str = "png";
-    let (buffer, w, h) = screenshot();
-    let local_storage = storage::Local::new();
-    let fullscreenshot = local_storage.save(
-        &buffer,
-        EXTENSION.into(),
-        u32::try_from(w).unwrap(),
-        u32::try_from(h).unwrap(),
-    ).unwrap();
-    let region = region::get_region(&fullscreenshot);
-    let (buffer, w, h) = img::crop(&fullscreenshot, region.unwrap()).unwrap();
-    let name = format!("{}.{}", storage.hash(&buffer), EXTENSION);
-    let destination = match storage.link(&name) {
-        Ok(it) => it,
-        _ => unreachable!(),
+    if let Err(e) = notif.show() {
+        eprintln!("Couldn't show notification: {}", e);
+    }
+}
+
+/// Lets the user know an image couldn't be saved, and why. It stays up until
+/// it's dismissed since nothing was copied.
+fn notify_failure(e: &Error) {
+    let mut notif = Notification::new();
+    notif
+        .summary("Sampic couldn't save the image.")
+        .body(&e.to_string())
+        .icon("dialog-error")
+        .timeout(Timeout::Never);
+    if let Err(e) = notif.show() {
+        eprintln!("Couldn't show notification: {}", e);
+    }
+}
+
+/// Waits out `delay` with a notification counting down the seconds left. It
+/// goes away by itself once the delay is over, so it isn't captured. Only
+/// Linux notifications can be updated, elsewhere the first count stays up.
+fn countdown(delay: Duration) {
+    let end = Instant::now() + delay;
+    // Time left, and the whole seconds shown for it, rounded up.
+    let left = || {
+        let left = end.saturating_duration_since(Instant::now());
+        (left, (left.as_millis() as u64 + 999) / 1000)
     };
-    let mut clipboard = Clipboard::new().unwrap();
-    clipboard.set_text(destination.clone()).unwrap();
-    notify(
-        &destination,
-        "Copied URL to clipboard. Uploading to server...",
-    );
-    storage
-        .save(
-            &buffer,
-            EXTENSION.into(),
-            u32::try_from(w).unwrap(),
-            u32::try_from(h).unwrap(),
-        )
-        .unwrap_or("error while saving file".to_string());
-    notify(&destination, "Uploaded!");
-    return destination;
+    let message = |seconds: u64| match seconds {
+        1 => "Taking a screenshot in 1 second.".to_string(),
+        seconds => format!("Taking a screenshot in {} seconds.", seconds),
+    };
+    let timeout =
+        |left: Duration| Timeout::Milliseconds(u32::try_from(left.as_millis()).unwrap_or(u32::MAX));
+    let (remaining, seconds) = left();
+    let mut notif = Notification::new();
+    notif
+        .summary("Sampic screenshot")
+        .body(&message(seconds))
+        .icon("camera")
+        .timeout(timeout(remaining));
+    #[cfg(target_os = "linux")]
+    notif.hint(Hint::Transient(true));
+    let shown = notif
+        .show()
+        .map_err(|e| eprintln!("Couldn't show countdown: {}", e))
+        .ok();
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(mut handle) = shown {
+            loop {
+                let (remaining, seconds) = left();
+                if seconds <= 1 {
+                    break;
+                }
+                // Until the count goes down by one.
+                std::thread::sleep(remaining - Duration::from_secs(seconds - 1));
+                let (remaining, seconds) = left();
+                handle.body(&message(seconds)).timeout(timeout(remaining));
+                handle.update();
+            }
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    drop(shown);
+    std::thread::sleep(end.saturating_duration_since(Instant::now()));
+}
+
+/// Part of the screen a screenshot keeps.
+#[derive(Clone, Debug)]
+pub enum Region {
+    /// Let the user select it.
+    Pick,
+    /// `[x, y, w, h]` in pixels of the captured screen.
+    Fixed([f64; 4]),
+    Fullscreen,
+    /// Whatever region the previous screenshot used.
+    Last,
+    /// Bounds of the window with this id or name, see `windows::find`.
+    Window(String),
+}
+
+impl Region {
+    /// Parses `x,y,w,h`.
+    pub fn parse(value: &str) -> Option<Region> {
+        let numbers: Vec<u32> = value
+            .split(',')
+            .map(|number| number.trim().parse().ok())
+            .collect::<Option<Vec<u32>>>()?;
+        match numbers.as_slice() {
+            &[x, y, w, h] if w > 0 && h > 0 => Some(Region::Fixed([
+                f64::from(x),
+                f64::from(y),
+                f64::from(w),
+                f64::from(h),
+            ])),
+            _ => None,
+        }
+    }
 }
 
-pub fn local_screenshot() -> String {
-    sampic_screenshot(storage::Local::new())
+/// How a screenshot is taken and saved.
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    pub encoding: img::Encoding,
+    pub displays: img::Displays,
+    pub region: Region,
+    /// Open the annotation editor before saving.
+    pub annotate: bool,
+    /// Cover text matching the configured redaction patterns before saving.
+    pub redact: bool,
+    /// Wait this long before capturing, e.g. to open a menu.
+    pub delay: Duration,
+    /// Show a notification counting down the delay.
+    pub countdown: bool,
 }
 
-pub fn s3_screenshot() -> String {
-    sampic_screenshot(storage::S3Store::new().expect("Error while stablishing S3 connection"))
+impl CaptureOptions {
+    pub fn new(encoding: img::Encoding) -> Self {
+        CaptureOptions {
+            encoding,
+            displays: img::Displays::Primary,
+            region: Region::Pick,
+            annotate: false,
+            redact: false,
+            delay: Duration::from_secs(0),
+            countdown: false,
+        }
+    }
+}
+
+/// Screenshot of `displays` saved locally for the region picker to show,
+/// with its width and height.
+fn fullscreenshot(displays: img::Displays) -> error::Result<(String, usize, usize)> {
+    let (buffer, w, h) = screenshot(displays)
+        .map_err(|e| Error::new(Class::Capture, "Couldn't capture the screen").with_source(e))?;
+    let local_storage = storage::Local::new().context("Couldn't set up local storage")?;
+    // Only read back by the region picker, so it's saved as quickly as possible.
+    let scratch = img::Encoding {
+        png_compression: "fast".into(),
+        ..img::Encoding::new("png")
+    };
+    let path = local_storage
+        .save(&buffer, &scratch, w as u32, h as u32)
+        .context("Couldn't save the screenshot for the region selector")?
+        .link;
+    Ok((path, w, h))
+}
+
+/// Resolves `choice` on the `w` by `h` screenshot of `displays` saved at
+/// `fullscreenshot`, asking the user to pick a region if needed.
+fn select(
+    choice: &Region,
+    displays: img::Displays,
+    fullscreenshot: &str,
+    w: usize,
+    h: usize,
+) -> error::Result<[f64; 4]> {
+    let spanning = match displays {
+        img::Displays::All => img::display_count() > 1,
+        _ => false,
+    };
+    // Window positions are relative to the X root window, screenshots start
+    // at their display's corner.
+    let origin = img::origin(displays);
+    let snap_to = match origin {
+        Some(origin) => windows::list()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|window| window.relative_to(origin))
+            .collect(),
+        None => Vec::new(),
+    };
+    let selected = match choice {
+        Region::Pick => match region::get_region(fullscreenshot, spanning, &snap_to)? {
+            Some(region) => region,
+            None => return Err(Error::cancelled()),
+        },
+        Region::Fixed(fixed) => *fixed,
+        Region::Fullscreen => [0.0, 0.0, w as f64, h as f64],
+        Region::Last => region::last_region()
+            .ok_or_else(|| Error::new(Class::Input, "No previous region to reuse"))?,
+        Region::Window(query) => {
+            let origin = origin.ok_or_else(|| {
+                Error::new(Class::Capture, "Couldn't tell where the displays are")
+            })?;
+            let window = windows::find(query)
+                .context(format!("Couldn't find window {}", query))?
+                .relative_to(origin);
+            region::clip(window.rect, [w as f64, h as f64]).ok_or_else(|| {
+                Error::new(Class::Input, format!("Window {} is off screen", query))
+            })?
+        }
+    };
+    let [x, y, width, height] = selected;
+    if x + width > w as f64 || y + height > h as f64 {
+        return Err(Error::new(
+            Class::Input,
+            format!(
+                "Region {}x{} at {},{} doesn't fit in the {}x{} screen",
+                width, height, x, y, w, h
+            ),
+        ));
+    }
+    region::remember(selected);
+    Ok(selected)
+}
+
+pub fn sampic_screenshot<T: 'static + Storage + std::marker::Send>(
+    storage: T,
+    options: &CaptureOptions,
+) -> error::Result<String> {
+    if options.countdown && options.delay > Duration::from_secs(0) {
+        countdown(options.delay);
+    } else {
+        std::thread::sleep(options.delay);
+    }
+    let (fullscreenshot, w, h) = fullscreenshot(options.displays)?;
+    let cropped =
+        select(&options.region, options.displays, &fullscreenshot, w, h).and_then(|region| {
+            img::crop(&fullscreenshot, region)
+                .ok_or_else(|| Error::new(Class::Capture, "Couldn't crop the screenshot"))
+        });
+    // Only read back to pick and crop the region. It's removed before
+    // anything is stored, since a local screenshot of the same pixels gets
+    // the same name.
+    std::fs::remove_file(&fullscreenshot).ok();
+    let (buffer, w, h) = cropped?;
+    let (w, h) = (w as u32, h as u32);
+    let buffer = if options.annotate {
+        match annotate::annotate(buffer, w, h)? {
+            Some(annotated) => annotated,
+            None => return Err(Error::cancelled()),
+        }
+    } else {
+        buffer
+    };
+    // Fails closed: a screenshot that should be redacted is never saved
+    // unredacted.
+    let (buffer, note) = if options.redact {
+        let cfg = config::config()?;
+        let (buffer, summary) = redact::redact(buffer, w, h, &cfg)?;
+        let note = match summary.as_str() {
+            "" => None,
+            _ => Some(format!("Redacted {}.", summary)),
+        };
+        (buffer, note)
+    } else {
+        (buffer, None)
+    };
+    let (name, data) = storage
+        .encode(&buffer, &options.encoding, w, h)
+        .map_err(|e| unencodable(&options.encoding, e))?;
+    // The backend decides the final link (the sampic server names uploads
+    // itself), so it's only known once the upload is done.
+    let saved = store(&storage, &name, &data, "screenshot", true)?;
+    Ok(publish(
+        &storage,
+        saved,
+        Some(&buffer),
+        Shared::Still,
+        w,
+        h,
+        note,
+    ))
 }
 
-pub fn upload_screenshot() -> String {
-    sampic_screenshot(
-        storage::SampicServer::new()
-            .expect("Error while stablishing connection with sampic server."),
+/// Error for a capture that `Storage::encode` couldn't encode.
+fn unencodable(encoding: &img::Encoding, e: storage::StorageError) -> Error {
+    Error::new(
+        Class::Capture,
+        format!("Couldn't encode the image as {}", encoding.format),
     )
+    .with_source(e)
+}
+
+/// Stores the `what` image with `storage`. If that fails nothing is copied,
+/// so the clipboard keeps what it held, the user is notified of the reason,
+/// and with `keep` the image is saved locally so it can be retried with
+/// `sampic push`.
+fn store(
+    storage: &dyn Storage,
+    name: &str,
+    data: &[u8],
+    what: &str,
+    keep: bool,
+) -> error::Result<storage::Saved> {
+    let name = name.to_string();
+    let e = match storage.store(&name, data) {
+        Ok(saved) => return Ok(saved),
+        Err(e) => Error::from(e),
+    };
+    let kept = match storage.kind() {
+        "local" => None,
+        _ if keep => storage::Local::new()
+            .and_then(|local| local.store(&name, data))
+            .map_err(|e| eprintln!("Couldn't keep a local copy: {}", e))
+            .ok(),
+        _ => None,
+    };
+    let context = match kept {
+        Some(kept) => format!(
+            "Couldn't save the {}, a copy was kept at {} (retry with `sampic push -s {} {}`)",
+            what,
+            kept.link,
+            storage.kind(),
+            kept.link
+        ),
+        None => format!("Couldn't save the {}", what),
+    };
+    let e = Error::new(e.class(), context).with_source(e);
+    notify_failure(&e);
+    Err(e)
+}
+
+/// What `publish` shares, which decides whether the image itself can be
+/// copied when `clipboard` is `image`. When it can't, its link is.
+#[derive(Clone, Copy, PartialEq)]
+enum Shared {
+    /// A still image, copied as it is.
+    Still,
+    /// A recording or another animation, of which only the first frame
+    /// would be copied.
+    Animated,
+    /// An image taken from the clipboard, which its link replaces.
+    Pasted,
+}
+
+/// Records a saved image in the history, copies its link, or whatever the
+/// `clipboard` setting asks for, and lets the user know it's there,
+/// mentioning `note` if there is one. `buffer` holds its pixels, if they
+/// could be decoded, and the image itself is only copied when they're all
+/// there is to it. The image is saved by then, so failing to do any of that
+/// is only reported.
+fn publish(
+    storage: &dyn Storage,
+    saved: storage::Saved,
+    buffer: Option<&[u8]>,
+    shared: Shared,
+    w: u32,
+    h: u32,
+    note: Option<String>,
+) -> String {
+    let recorded = history::History::open().and_then(|history| {
+        let animated = shared == Shared::Animated;
+        history.record(&saved, storage.kind(), buffer, animated, w, h)
+    });
+    // The deletion token is only handed out once, so it must not get lost.
+    match (recorded, &saved.delete_token) {
+        (Ok(entry), Some(_)) => {
+            eprintln!("Delete it with `sampic history delete {}`.", entry.id)
+        }
+        (Ok(_), None) => {}
+        (Err(e), token) => {
+            let what = match shared {
+                Shared::Animated => "recording",
+                _ => "screenshot",
+            };
+            eprintln!("Couldn't add {} to history: {}", what, e);
+            if let Some(token) = token {
+                eprintln!(
+                    "Delete it with `sampic delete --storage {} --token {} {}`.",
+                    storage.kind(),
+                    token,
+                    saved.link
+                );
+            }
+        }
+    }
+    let cfg = config::config().unwrap_or_default();
+    let details = snippet::Details {
+        url: &saved.link,
+        name: &saved.name,
+        width: w,
+        height: h,
+    };
+    let still = buffer.filter(|_| shared == Shared::Still);
+    let action = match still {
+        None if cfg.clipboard == "image" => "url",
+        _ => cfg.clipboard.as_str(),
+    };
+    let copied = Clipboard::new().and_then(|mut clipboard| {
+        match (
+            snippet::text(action, &cfg.clipboard_template, &details),
+            still,
+        ) {
+            (None, Some(buffer)) => clipboard.set_image(ImageData {
+                width: w as usize,
+                height: h as usize,
+                bytes: Cow::Borrowed(buffer),
+            }),
+            (text, _) => clipboard.set_text(text.unwrap_or_else(|| saved.link.clone())),
+        }
+    });
+    let copied = match copied {
+        Ok(()) => format!(
+            "Uploaded! Copied {} to clipboard.",
+            snippet::description(action)
+        ),
+        Err(e) => {
+            eprintln!("Couldn't copy to the clipboard: {}", e);
+            "Uploaded!".to_string()
+        }
+    };
+    let destination = saved.link;
+    let message = match note {
+        Some(note) => format!("{} {}", copied, note),
+        None => copied,
+    };
+    notify(&destination, &message);
+    return destination;
+}
+
+/// Sends an existing image file, or stdin if `path` is `-`, to a backend
+/// without taking a screenshot. The image is stored exactly as it was read.
+pub fn push(backend: &str, path: &str) -> error::Result<String> {
+    let storage =
+        client_backend(backend, None).context(format!("Couldn't set up {} storage", backend))?;
+    let mut data = Vec::new();
+    let read = if path == "-" {
+        std::io::stdin().read_to_end(&mut data).map(|_| ())
+    } else {
+        std::fs::read(path).map(|contents| data = contents)
+    };
+    read.map_err(|e| Error::new(Class::Input, format!("Couldn't read {}", path)).with_source(e))?;
+    let extension = img::extension(&data).ok_or_else(|| {
+        Error::new(
+            Class::Input,
+            format!("{} isn't a PNG, JPEG, GIF, WebP, BMP or AVIF image", path),
+        )
+    })?;
+    // Named like the server names uploads, so pushing a copy kept by
+    // `record` gives the link it would have had.
+    let (name, pixels, shared, w, h) = match img::check(&data, extension) {
+        Some(img::Checked::Pixels(pixels, w, h)) => {
+            (storage.hash(&pixels), Some(pixels), Shared::Still, w, h)
+        }
+        Some(img::Checked::Animated(w, h)) => {
+            // The first frame of a GIF or APNG still makes a thumbnail.
+            let first = img::decode(&data, extension).map(|(pixels, _, _)| pixels);
+            (storage.hash(&data), first, Shared::Animated, w, h)
+        }
+        Some(img::Checked::Avif(w, h)) => (storage.hash(&data), None, Shared::Still, w, h),
+        None => {
+            return Err(Error::new(
+                Class::Input,
+                format!("Couldn't decode {}", path),
+            ))
+        }
+    };
+    let name = format!("{}.{}", name, extension);
+    // A file can be pushed again as it is, only stdin is gone.
+    let saved = store(storage.as_ref(), &name, &data, "image", path == "-")?;
+    Ok(publish(
+        storage.as_ref(),
+        saved,
+        pixels.as_deref(),
+        shared,
+        w,
+        h,
+        None,
+    ))
+}
+
+/// Saves the image on the clipboard, e.g. copied from a browser or another
+/// screenshot tool, to `backend`. Its link then replaces it on the clipboard.
+pub fn clipboard(backend: &str, encoding: &img::Encoding) -> error::Result<String> {
+    let storage =
+        client_backend(backend, None).context(format!("Couldn't set up {} storage", backend))?;
+    let image = Clipboard::new()
+        .and_then(|mut clipboard| clipboard.get_image())
+        .map_err(|e| {
+            Error::new(Class::Input, "The clipboard doesn't hold an image").with_source(e)
+        })?;
+    let (w, h) = (image.width as u32, image.height as u32);
+    let (name, data) = storage
+        .encode(&image.bytes, encoding, w, h)
+        .map_err(|e| unencodable(encoding, e))?;
+    let saved = store(storage.as_ref(), &name, &data, "clipboard image", true)?;
+    Ok(publish(
+        storage.as_ref(),
+        saved,
+        Some(&image.bytes),
+        Shared::Pasted,
+        w,
+        h,
+        None,
+    ))
+}
+
+/// Records a region of the screen until it's stopped and saves it to
+/// `backend` like a screenshot.
+pub fn record(backend: &str, options: &record::RecordOptions) -> error::Result<String> {
+    let storage =
+        client_backend(backend, None).context(format!("Couldn't set up {} storage", backend))?;
+    if let img::Displays::All = options.displays {
+        return Err(record::RecordError::Unsupported.into());
+    }
+    let seconds = options.duration.as_secs_f64();
+    if seconds < 1.0 || seconds > record::MAX_SECONDS as f64 {
+        return Err(Error::new(
+            Class::Input,
+            format!(
+                "Recordings last from 1 to {} seconds, not {}",
+                record::MAX_SECONDS,
+                seconds
+            ),
+        ));
+    }
+    let (fullscreenshot, w, h) = fullscreenshot(options.displays)?;
+    let region = select(&options.region, options.displays, &fullscreenshot, w, h);
+    std::fs::remove_file(&fullscreenshot).ok();
+    let region = region?;
+    eprintln!("Recording, press Enter or run `sampic record --stop` to stop.");
+    let frames = record::frames(options, region)?;
+    let (data, extension) = record::encode(&frames, &options.format)?;
+    let name = format!("{}.{}", storage.hash(&data), extension);
+    let saved = store(storage.as_ref(), &name, &data, "recording", true)?;
+    let first = &frames[0].image;
+    Ok(publish(
+        storage.as_ref(),
+        saved,
+        Some(first.as_raw()),
+        Shared::Animated,
+        first.width(),
+        first.height(),
+        None,
+    ))
+}
+
+/// Takes `count` screenshots `interval` apart, each one taken and saved by
+/// `capture`. A region picked for the first one is reused for the rest so the
+/// series runs unattended, and the delay only applies to the first one.
+/// Cancelling the first screenshot stops the series.
+pub fn series<F>(
+    options: &CaptureOptions,
+    interval: Duration,
+    count: usize,
+    capture: F,
+) -> Vec<error::Result<String>>
+where
+    F: Fn(&CaptureOptions) -> error::Result<String>,
+{
+    let mut results = Vec::new();
+    let mut options = options.clone();
+    let mut next = Instant::now();
+    for index in 0..count {
+        if index > 0 {
+            std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+        let result = capture(&options);
+        if index == 0 {
+            if let Err(e) = &result {
+                if e.class() == Class::Cancelled {
+                    results.push(result);
+                    break;
+                }
+            }
+            // The interval is counted from the first capture, not from the
+            // start of its delay.
+            next = Instant::now();
+            options.delay = Duration::from_secs(0);
+            options.countdown = false;
+            if let Region::Pick = options.region {
+                options.region = Region::Last;
+            }
+        }
+        results.push(result);
+        next += interval;
+    }
+    results
+}
+
+pub fn local_screenshot(options: &CaptureOptions) -> error::Result<String> {
+    let storage = storage::Local::new().context("Couldn't set up local storage")?;
+    sampic_screenshot(storage, options)
+}
+
+pub fn s3_screenshot(options: &CaptureOptions) -> error::Result<String> {
+    let storage = storage::S3Store::new().context("Couldn't connect to S3")?;
+    sampic_screenshot(storage, options)
+}
+
+pub fn upload_screenshot(options: &CaptureOptions) -> error::Result<String> {
+    let storage = storage::SampicServer::new().context("Couldn't set up the sampic server")?;
+    sampic_screenshot(storage, options)
+}
+
+/// Deletes a screenshot, given its URL or name, from the backend it was saved to.
+/// Screenshots sent to a sampic server also need the deletion token it handed out.
+pub fn delete(backend: &str, target: &str, delete_token: Option<String>) -> error::Result<()> {
+    Ok(remove(backend, target, delete_token)?)
+}
+
+fn remove(
+    backend: &str,
+    target: &str,
+    delete_token: Option<String>,
+) -> Result<(), storage::StorageError> {
+    let path = target.split('?').next().unwrap_or(target);
+    let name = path.rsplit('/').next().unwrap_or(path).to_string();
+    client_backend(backend, delete_token)?.delete(&name)
+}
+
+/// Any backend the CLI can save to: `local`, `s3` or `upload` for a sampic
+/// server.
+fn client_backend(
+    backend: &str,
+    delete_token: Option<String>,
+) -> Result<Box<dyn Storage>, storage::StorageError> {
+    let storage: Box<dyn Storage> = match (backend, delete_token) {
+        ("upload", Some(token)) => Box::new(storage::SampicServer::new()?.with_delete_token(token)),
+        ("upload", None) => Box::new(storage::SampicServer::new()?),
+        (backend, _) => storage::backend(backend)?,
+    };
+    Ok(storage)
+}
+
+/// Opens a URL or file with the desktop's default application.
+pub fn open(target: &str) -> std::io::Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = std::process::Command::new("cmd");
+        command.args(&["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+    command.arg(target).spawn()?;
+    Ok(())
+}
+
+pub fn copy(text: &str) -> error::Result<()> {
+    Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text.to_string()))
+        .map_err(|e| Error::new(Class::Other, "Couldn't copy to the clipboard").with_source(e))
+}
+
+/// Deletes a screenshot recorded in the history, then its entry.
+pub fn delete_from_history(id: u64) -> error::Result<history::Entry> {
+    let history = history::History::open()?;
+    let entry = history.find(id)?;
+    match remove(&entry.backend, &entry.url, entry.delete_token.clone()) {
+        Ok(()) | Err(storage::StorageError::NotFound) => {}
+        Err(e) => return Err(Error::from(e)),
+    }
+    history.forget(id)?;
+    Ok(entry)
 }
 
 #[cfg(test)]
@@ -538,4 +2606,117 @@ mod tests {
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn regions_parse_as_x_y_w_h() {
+        match super::Region::parse("10, 20,300,400") {
+            Some(super::Region::Fixed(region)) => assert_eq!(region, [10.0, 20.0, 300.0, 400.0]),
+            _ => panic!("expected a fixed region"),
+        }
+        assert!(super::Region::parse("10,20,0,400").is_none());
+        assert!(super::Region::parse("10,20,300").is_none());
+        assert!(super::Region::parse("-1,20,300,400").is_none());
+    }
+
+    #[test]
+    fn monitors_are_cut_out_of_the_root_window() {
+        use super::img::{area, cut, Displays};
+        use super::windows::Monitor;
+        let monitor = |x, y, w, h, primary| Monitor {
+            name: String::new(),
+            primary,
+            rect: [x, y, w, h],
+        };
+        // A 2x1 monitor with a 1x1 primary one below its right half.
+        let monitors = [
+            monitor(1.0, 0.0, 2.0, 1.0, false),
+            monitor(2.0, 1.0, 1.0, 1.0, true),
+        ];
+        assert_eq!(
+            area(Displays::Primary, &monitors),
+            Some([2.0, 1.0, 1.0, 1.0])
+        );
+        assert_eq!(
+            area(Displays::Index(0), &monitors),
+            Some([1.0, 0.0, 2.0, 1.0])
+        );
+        assert_eq!(area(Displays::Index(2), &monitors), None);
+        assert_eq!(area(Displays::All, &monitors), Some([1.0, 0.0, 2.0, 2.0]));
+        assert_eq!(area(Displays::All, &[]), None);
+
+        // A 3x2 root window whose pixels are numbered 0 to 5.
+        let root: Vec<u8> = (0..6u8).flat_map(|i| vec![i; 4]).collect();
+        assert_eq!(
+            cut(&root, 3, 2, [2.0, 1.0, 1.0, 1.0]),
+            Some((vec![5; 4], 1, 1))
+        );
+        let (pixels, w, h) = cut(&root, 3, 2, [1.0, 0.0, 2.0, 2.0]).unwrap();
+        assert_eq!((w, h), (2, 2));
+        assert_eq!(pixels, [[1u8; 4], [2; 4], [4; 4], [5; 4]].concat());
+        assert!(cut(&root, 3, 2, [3.0, 0.0, 1.0, 1.0]).is_none());
+    }
+
+    #[test]
+    #[cfg(not(target_os = "linux"))]
+    fn displays_are_stitched_left_to_right() {
+        use super::img::{layout, stitch};
+        let sizes = [(2, 1), (1, 2)];
+        assert_eq!(layout(&sizes), vec![[0, 0], [2, 0]]);
+
+        let white = [255u8; 4];
+        let frames = [(white.repeat(2), 2, 1), (white.repeat(2), 1, 2)];
+        let (canvas, w, h) = stitch(&frames, &layout(&sizes));
+        assert_eq!((w, h), (3, 2));
+        let black = [0, 0, 0, 255];
+        assert_eq!(canvas, [white, white, white, black, black, white].concat());
+    }
+
+    #[test]
+    fn gifs_are_animated_when_they_have_several_frames() {
+        use super::img::{frames, Frames};
+        let mut data = Vec::new();
+        {
+            // Plays once, no NETSCAPE2.0 extension.
+            let mut encoder = gif::Encoder::new(&mut data, 2, 2, &[]).unwrap();
+            for color in [[255u8, 0, 0, 255], [0, 0, 255, 255]].iter() {
+                let mut pixels = color.repeat(4);
+                let frame = gif::Frame::from_rgba(2, 2, &mut pixels);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        assert_eq!(frames(&data, "gif"), Frames::Animated(2, 2));
+        assert_eq!(frames(&data, "png"), Frames::Invalid);
+        data.truncate(data.len() - 4);
+        assert_eq!(frames(&data, "gif"), Frames::Invalid);
+    }
+
+    #[test]
+    fn gif_frames_larger_than_the_limit_are_rejected() {
+        use super::img::{frames, Frames};
+        let mut data = Vec::new();
+        {
+            let mut encoder = gif::Encoder::new(&mut data, 1, 1, &[]).unwrap();
+            let mut pixels = vec![255u8; 4];
+            encoder
+                .write_frame(&gif::Frame::from_rgba(1, 1, &mut pixels))
+                .unwrap();
+        }
+        assert_eq!(frames(&data, "gif"), Frames::Still);
+        // A 1x1 canvas whose only frame claims to be 65535x65535.
+        let descriptor = 13 + data[13..].iter().position(|&byte| byte == 0x2C).unwrap();
+        data[descriptor + 5..descriptor + 9].copy_from_slice(&[0xFF; 4]);
+        assert_eq!(frames(&data, "gif"), Frames::Invalid);
+    }
+
+    #[test]
+    fn avif_sizes_are_read_from_well_formed_boxes() {
+        use super::img::{avif_dimensions, encode, Encoding};
+        let data = encode(&[90u8; 8 * 4 * 4], 8, 4, &Encoding::new("avif")).unwrap();
+        assert_eq!(avif_dimensions(&data), Some((8, 4)));
+        assert_eq!(avif_dimensions(&data[..data.len() - 1]), None);
+        // The right bytes in the wrong places.
+        let mut forged = b"\0\0\0\x10ftypavif\0\0\0\0".to_vec();
+        forged.extend_from_slice(b"\0\0\0\x14ispe\0\0\0\0\0\0\0\x08\0\0\0\x04");
+        assert_eq!(avif_dimensions(&forged), None);
+    }
 }
@@ -0,0 +1,308 @@
+//! Per-client rate limits and storage quotas for the upload server.
+//!
+//! Every upload is charged to the uploader's IP address and to the API key it
+//! used: everything it sends counts against the daily quota, and what ends up
+//! stored against the storage quota. Counters are written to `usage.toml` in
+//! sampic's data directory after every change, so a restart doesn't reset
+//! anybody's quota.
+use super::config::SampConf;
+use super::datafile::{self, DataError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const MINUTE: u64 = 60;
+const DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug)]
+pub enum LimitError {
+    /// The request went over one of its client's limits.
+    Exceeded(Exceeded),
+    Data(DataError),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitError::Exceeded(exceeded) => write!(f, "{}", exceeded),
+            LimitError::Data(e) => write!(f, "usage.toml: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LimitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LimitError::Data(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<DataError> for LimitError {
+    fn from(e: DataError) -> LimitError {
+        LimitError::Data(e)
+    }
+}
+
+/// Limits applied to a single client. Zero means unlimited.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub requests_per_minute: u64,
+    pub bytes_per_day: u64,
+    pub stored_bytes: u64,
+}
+
+impl Limits {
+    pub fn per_ip(cfg: &SampConf) -> Self {
+        Limits {
+            requests_per_minute: cfg.ip_requests_per_minute,
+            bytes_per_day: cfg.ip_bytes_per_day,
+            stored_bytes: cfg.ip_stored_bytes,
+        }
+    }
+
+    pub fn per_key(cfg: &SampConf) -> Self {
+        Limits {
+            requests_per_minute: cfg.key_requests_per_minute,
+            bytes_per_day: cfg.key_bytes_per_day,
+            stored_bytes: cfg.key_stored_bytes,
+        }
+    }
+}
+
+/// Who an upload is charged to.
+pub enum Client<'a> {
+    Ip(IpAddr),
+    Key(&'a str),
+}
+
+impl<'a> Client<'a> {
+    fn id(&self) -> String {
+        match self {
+            Client::Ip(ip) => format!("ip:{}", ip),
+            Client::Key(name) => format!("key:{}", name),
+        }
+    }
+}
+
+/// A request went over one of its client's limits.
+#[derive(Debug)]
+pub struct Exceeded {
+    pub retry_after: u64,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Exceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, retry in {} seconds", self.reason, self.retry_after)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct Usage {
+    minute: u64,
+    requests: u64,
+    day: u64,
+    bytes_today: u64,
+    stored_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UsageTable {
+    clients: HashMap<String, Usage>,
+}
+
+pub struct Limiter {
+    ip: Limits,
+    key: Limits,
+    path: Option<PathBuf>,
+    usage: Mutex<UsageTable>,
+    /// Seconds since the epoch.
+    clock: fn() -> u64,
+    trust_proxy: bool,
+}
+
+impl Limiter {
+    /// Limiter whose counters are kept in sampic's data directory.
+    pub fn open(ip: Limits, key: Limits) -> Result<Self, LimitError> {
+        Limiter::at(ip, key, Some(datafile::data_dir()?.join("usage.toml")))
+    }
+
+    /// Limiter whose counters are kept at `path`, or only in memory if `None`.
+    pub fn at(ip: Limits, key: Limits, path: Option<PathBuf>) -> Result<Self, LimitError> {
+        let table = match &path {
+            Some(path) => datafile::load(path)?,
+            None => UsageTable::default(),
+        };
+        Ok(Limiter {
+            ip,
+            key,
+            path,
+            usage: Mutex::new(table),
+            clock: datafile::now,
+            trust_proxy: false,
+        })
+    }
+
+    /// Reads the time from `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Charges uploads to the IP in `X-Real-IP` instead of the peer's
+    /// address. Only safe behind a reverse proxy that sets it, anyone can
+    /// send it otherwise.
+    pub fn trust_proxy(mut self, trust: bool) -> Self {
+        self.trust_proxy = trust;
+        self
+    }
+
+    pub fn trusts_proxy(&self) -> bool {
+        self.trust_proxy
+    }
+
+    fn limits(&self, client: &Client) -> Limits {
+        match client {
+            Client::Ip(_) => self.ip,
+            Client::Key(_) => self.key,
+        }
+    }
+
+    fn persist(&self, table: &UsageTable) -> Result<(), LimitError> {
+        if let Some(path) = &self.path {
+            datafile::save(path, table)?;
+        }
+        Ok(())
+    }
+
+    /// Counts a new request against every client, rejecting it if any of them
+    /// already used up this minute's requests.
+    pub fn request(&self, clients: &[Client]) -> Result<(), LimitError> {
+        let now = (self.clock)();
+        let mut table = self.usage.lock().unwrap();
+        for client in clients {
+            let limit = self.limits(client).requests_per_minute;
+            let usage = table.clients.entry(client.id()).or_default();
+            if usage.minute != now / MINUTE {
+                usage.minute = now / MINUTE;
+                usage.requests = 0;
+            }
+            if limit != 0 && usage.requests >= limit {
+                return Err(LimitError::Exceeded(Exceeded {
+                    retry_after: MINUTE - now % MINUTE,
+                    reason: "Too many requests",
+                }));
+            }
+        }
+        for client in clients {
+            table.clients.entry(client.id()).or_default().requests += 1;
+        }
+        self.persist(&table)
+    }
+
+    /// Bytes every client may still send today, `u64::MAX` if none of them
+    /// has a daily limit.
+    pub fn remaining(&self, clients: &[Client]) -> u64 {
+        let day = (self.clock)() / DAY;
+        let table = self.usage.lock().unwrap();
+        let mut remaining = u64::MAX;
+        for client in clients {
+            let limits = self.limits(client);
+            let usage = table.clients.get(&client.id()).cloned().unwrap_or_default();
+            let today = if usage.day == day {
+                usage.bytes_today
+            } else {
+                0
+            };
+            if limits.bytes_per_day != 0 {
+                remaining = remaining.min(limits.bytes_per_day.saturating_sub(today));
+            }
+        }
+        remaining
+    }
+
+    /// Charges `bytes` received to every client's daily quota, whether the
+    /// upload ends up stored or not, and rejects the upload if that went
+    /// over any of them.
+    pub fn receive(&self, clients: &[Client], bytes: u64) -> Result<(), LimitError> {
+        let now = (self.clock)();
+        let mut table = self.usage.lock().unwrap();
+        let mut exceeded = None;
+        for client in clients {
+            let limits = self.limits(client);
+            let usage = table.clients.entry(client.id()).or_default();
+            if usage.day != now / DAY {
+                usage.day = now / DAY;
+                usage.bytes_today = 0;
+            }
+            usage.bytes_today = usage.bytes_today.saturating_add(bytes);
+            if limits.bytes_per_day != 0 && usage.bytes_today > limits.bytes_per_day {
+                exceeded = Some(Exceeded {
+                    retry_after: DAY - now % DAY,
+                    reason: "Daily upload quota exceeded",
+                });
+            }
+        }
+        self.persist(&table)?;
+        match exceeded {
+            Some(exceeded) => Err(LimitError::Exceeded(exceeded)),
+            None => Ok(()),
+        }
+    }
+
+    /// Charges `bytes` to every client's storage quota, rejecting the upload
+    /// without charging anything if it would go over any of them, or if the
+    /// charge couldn't be saved.
+    pub fn reserve(&self, clients: &[Client], bytes: u64) -> Result<(), LimitError> {
+        let mut table = self.usage.lock().unwrap();
+        for client in clients {
+            let limits = self.limits(client);
+            let usage = table.clients.entry(client.id()).or_default();
+            if limits.stored_bytes != 0 && usage.stored_bytes + bytes > limits.stored_bytes {
+                // Space is only freed by deleting uploads, so there is no
+                // better estimate than trying again tomorrow.
+                return Err(LimitError::Exceeded(Exceeded {
+                    retry_after: DAY,
+                    reason: "Storage quota exceeded",
+                }));
+            }
+        }
+        for client in clients {
+            table.clients.entry(client.id()).or_default().stored_bytes += bytes;
+        }
+        if let Err(e) = self.persist(&table) {
+            for client in clients {
+                table.clients.entry(client.id()).or_default().stored_bytes -= bytes;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Bytes currently stored by `client`.
+    pub fn stored(&self, client: &Client) -> u64 {
+        let table = self.usage.lock().unwrap();
+        table
+            .clients
+            .get(&client.id())
+            .map(|usage| usage.stored_bytes)
+            .unwrap_or(0)
+    }
+
+    /// Gives back storage quota charged by `reserve`, after an upload was
+    /// deleted or couldn't be stored.
+    pub fn free(&self, clients: &[Client], bytes: u64) -> Result<(), LimitError> {
+        let mut table = self.usage.lock().unwrap();
+        for client in clients {
+            if let Some(usage) = table.clients.get_mut(&client.id()) {
+                usage.stored_bytes = usage.stored_bytes.saturating_sub(bytes);
+            }
+        }
+        self.persist(&table)
+    }
+}
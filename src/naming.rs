@@ -0,0 +1,65 @@
+//! How stored objects are named.
+//!
+//! Names are either a cryptographic hash of the image, so the same screenshot
+//! always gets the same name, or random, so that links to private screenshots
+//! can't be predicted or enumerated.
+use super::keys::random_token;
+use sha2::{Digest, Sha256};
+
+pub const SCHEMES: [&str; 3] = ["sha256", "blake3", "random"];
+pub const MIN_LENGTH: usize = 8;
+pub const MAX_LENGTH: usize = 64;
+
+/// A backend's naming settings, read from the configuration once when the
+/// backend is built.
+#[derive(Clone, Debug)]
+pub struct Naming {
+    pub scheme: String,
+    pub length: usize,
+}
+
+impl Naming {
+    pub fn name(&self, buffer: &[u8]) -> String {
+        name(buffer, &self.scheme, self.length)
+    }
+}
+
+impl Default for Naming {
+    fn default() -> Self {
+        Naming {
+            scheme: "sha256".into(),
+            length: 32,
+        }
+    }
+}
+
+/// Name for `buffer` using `scheme`, `length` characters long.
+pub fn name(buffer: &[u8], scheme: &str, length: usize) -> String {
+    let length = length.max(MIN_LENGTH).min(MAX_LENGTH);
+    let mut name = match scheme {
+        "random" => return random_token(length),
+        "blake3" => blake3::hash(buffer).to_hex().to_string(),
+        _ => format!("{:x}", Sha256::digest(buffer)),
+    };
+    name.truncate(length);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::name;
+
+    #[test]
+    fn hashes_are_stable_and_truncated() {
+        let sha = name(b"sampic", "sha256", 16);
+        assert_eq!(sha, name(b"sampic", "sha256", 16));
+        assert_eq!(sha.len(), 16);
+        assert_ne!(sha, name(b"sampic", "blake3", 16));
+        assert_eq!(name(b"sampic", "blake3", 1000).len(), 64);
+    }
+
+    #[test]
+    fn random_names_differ() {
+        assert_ne!(name(b"sampic", "random", 32), name(b"sampic", "random", 32));
+    }
+}
@@ -0,0 +1,395 @@
+//! Screen recordings of a region, saved as animated GIF, PNG or WebP.
+//!
+//! Frames are grabbed with the same scrap capturer screenshots use, at a fixed
+//! rate, until the recording is stopped or runs out of time. Consecutive
+//! identical frames are merged into one that's shown for longer, which keeps
+//! recordings of mostly still screens small.
+use super::config::SampConf;
+use super::datafile;
+use super::img::{self, Displays};
+use super::Region;
+use image::RgbaImage;
+use scrap::{Capturer, Display};
+use std::fmt;
+use std::io::ErrorKind::WouldBlock;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Accepted values for `--format` and `record_format`.
+pub const FORMATS: [&str; 3] = ["gif", "apng", "webp"];
+
+/// Longest recording, in seconds.
+pub const MAX_SECONDS: u64 = 600;
+
+/// Most memory uncompressed frames may take before the recording is stopped.
+/// Only frames that differ count, a fullscreen 1080p one takes about 8 MB.
+const MAX_FRAME_BYTES: usize = 1 << 30;
+
+#[derive(Debug)]
+pub enum RecordError {
+    CaptureError,
+    EncodeError,
+    NothingRecorded,
+    /// Recording several displays at once isn't supported.
+    Unsupported,
+    IOError(std::io::Error),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::CaptureError => write!(f, "Couldn't capture the screen"),
+            RecordError::EncodeError => write!(f, "Couldn't encode the recording"),
+            RecordError::NothingRecorded => write!(f, "The recording was stopped before it began"),
+            RecordError::Unsupported => write!(f, "Only one display can be recorded at a time"),
+            RecordError::IOError(e) => write!(f, "Couldn't read or write the stop file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecordError::IOError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for RecordError {
+    fn from(e: std::io::Error) -> RecordError {
+        RecordError::IOError(e)
+    }
+}
+
+/// What is recorded and how it's saved.
+#[derive(Clone, Debug)]
+pub struct RecordOptions {
+    /// One of `FORMATS`.
+    pub format: String,
+    pub fps: u32,
+    /// Longest the recording runs if it isn't stopped earlier.
+    pub duration: Duration,
+    pub displays: Displays,
+    pub region: Region,
+}
+
+impl RecordOptions {
+    pub fn from_config(cfg: &SampConf) -> Self {
+        RecordOptions {
+            format: cfg.record_format.clone(),
+            fps: cfg.record_fps as u32,
+            duration: Duration::from_secs(cfg.record_seconds),
+            displays: Displays::Primary,
+            region: Region::Pick,
+        }
+    }
+}
+
+/// A frame and how long it stays on screen.
+pub struct Frame {
+    pub image: RgbaImage,
+    pub delay: Duration,
+}
+
+/// Creating this file stops the running recording, so a desktop shortcut
+/// bound to `sampic record --stop` can end it.
+fn stop_file() -> Result<PathBuf, RecordError> {
+    Ok(datafile::dirs()?.data_dir().join("stop_recording"))
+}
+
+/// Asks a running recording to stop.
+pub fn stop() -> Result<(), RecordError> {
+    let path = stop_file()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, b"")?;
+    Ok(())
+}
+
+/// Set once Enter is pressed in the terminal. Nothing is set when stdin
+/// isn't a terminal and reads end right away.
+fn enter_pressed() -> Arc<AtomicBool> {
+    let pressed = Arc::new(AtomicBool::new(false));
+    let flag = pressed.clone();
+    thread::spawn(move || {
+        let mut line = String::new();
+        if let Ok(read) = std::io::stdin().read_line(&mut line) {
+            if read > 0 {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    pressed
+}
+
+/// The scrap display to record `displays` from, and `region` of them
+/// relative to its top left corner.
+fn display(displays: Displays, region: [f64; 4]) -> Result<(Display, [f64; 4]), RecordError> {
+    if let Displays::All = displays {
+        return Err(RecordError::Unsupported);
+    }
+    let (display, area) = img::source(displays).map_err(|_| RecordError::CaptureError)?;
+    let [x, y] = area.map_or([0.0, 0.0], |area| [area[0], area[1]]);
+    Ok((
+        display,
+        [region[0] + x, region[1] + y, region[2], region[3]],
+    ))
+}
+
+/// Copies `region` out of a BGRA frame, `stride` bytes per row, as RGBA.
+fn crop(buffer: &[u8], stride: usize, region: [f64; 4]) -> RgbaImage {
+    let [x, y, w, h] = [
+        region[0] as usize,
+        region[1] as usize,
+        region[2] as usize,
+        region[3] as usize,
+    ];
+    let mut pixels = Vec::with_capacity(w * h * 4);
+    for row in y..y + h {
+        for column in x..x + w {
+            let i = stride * row + 4 * column;
+            pixels.extend_from_slice(&[buffer[i + 2], buffer[i + 1], buffer[i], 255]);
+        }
+    }
+    RgbaImage::from_raw(w as u32, h as u32, pixels).unwrap()
+}
+
+/// Frames recorded so far. Consecutive identical frames are merged into one
+/// that's shown for longer.
+struct Recording {
+    frames: Vec<Frame>,
+    /// When the last frame was first captured.
+    shown: Instant,
+    interval: Duration,
+    bytes: usize,
+}
+
+impl Recording {
+    fn new(interval: Duration, start: Instant) -> Self {
+        Recording {
+            frames: Vec::new(),
+            shown: start,
+            interval,
+            bytes: 0,
+        }
+    }
+
+    /// Adds a frame captured at `now`. Returns false, dropping it, when there
+    /// is no memory left for it.
+    fn push(&mut self, image: RgbaImage, now: Instant) -> bool {
+        if let Some(previous) = self.frames.last_mut() {
+            if previous.image == image {
+                return true;
+            }
+            if self.bytes + image.as_raw().len() > MAX_FRAME_BYTES {
+                return false;
+            }
+            previous.delay = now - self.shown;
+        }
+        self.bytes += image.as_raw().len();
+        self.shown = now;
+        self.frames.push(Frame {
+            image,
+            delay: self.interval,
+        });
+        true
+    }
+
+    /// The frames of a recording stopped at `now`.
+    fn finish(mut self, now: Instant) -> Result<Vec<Frame>, RecordError> {
+        match self.frames.last_mut() {
+            Some(last) => last.delay = last.delay.max(now - self.shown),
+            None => return Err(RecordError::NothingRecorded),
+        }
+        Ok(self.frames)
+    }
+}
+
+/// Records `region` of the chosen display until Enter is pressed, `stop` is
+/// called, `options.duration` is over or the frames fill `MAX_FRAME_BYTES`.
+pub fn frames(options: &RecordOptions, region: [f64; 4]) -> Result<Vec<Frame>, RecordError> {
+    let (display, region) = display(options.displays, region)?;
+    let mut capturer = Capturer::new(display).map_err(|_| RecordError::CaptureError)?;
+    let h = capturer.height();
+    let interval = Duration::from_secs(1) / options.fps.max(1);
+    let stop_file = stop_file()?;
+    // Left over from a recording that ended on its own.
+    std::fs::remove_file(&stop_file).ok();
+    let enter = enter_pressed();
+    let start = Instant::now();
+    let mut next = start;
+    let mut recording = Recording::new(interval, start);
+    while start.elapsed() < options.duration
+        && !enter.load(Ordering::Relaxed)
+        && !stop_file.exists()
+    {
+        let buffer = match capturer.frame() {
+            Ok(buffer) => buffer,
+            Err(error) if error.kind() == WouldBlock => {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            Err(_) => return Err(RecordError::CaptureError),
+        };
+        let image = crop(&buffer, buffer.len() / h, region);
+        if !recording.push(image, Instant::now()) {
+            eprintln!("Stopped recording, there's no memory left for more frames.");
+            break;
+        }
+        next += interval;
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+    }
+    std::fs::remove_file(&stop_file).ok();
+    recording.finish(Instant::now())
+}
+
+fn gif(frames: &[Frame]) -> Result<Vec<u8>, RecordError> {
+    let (w, h) = frames[0].image.dimensions();
+    if w > u32::from(u16::MAX) || h > u32::from(u16::MAX) {
+        return Err(RecordError::EncodeError);
+    }
+    let mut data = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut data, w as u16, h as u16, &[])
+            .map_err(|_| RecordError::EncodeError)?;
+        encoder
+            .set_repeat(gif::Repeat::Infinite)
+            .map_err(|_| RecordError::EncodeError)?;
+        for frame in frames {
+            let mut pixels = frame.image.as_raw().clone();
+            let mut encoded = gif::Frame::from_rgba_speed(w as u16, h as u16, &mut pixels, 10);
+            // In hundredths of a second.
+            encoded.delay = (frame.delay.as_millis() / 10).min(u128::from(u16::MAX)) as u16;
+            encoder
+                .write_frame(&encoded)
+                .map_err(|_| RecordError::EncodeError)?;
+        }
+    }
+    Ok(data)
+}
+
+fn apng(frames: &[Frame]) -> Result<Vec<u8>, RecordError> {
+    let (w, h) = frames[0].image.dimensions();
+    let mut data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut data, w, h);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .set_animated(frames.len() as u32, 0)
+            .map_err(|_| RecordError::EncodeError)?;
+        let mut writer = encoder
+            .write_header()
+            .map_err(|_| RecordError::EncodeError)?;
+        for frame in frames {
+            let millis = frame.delay.as_millis().min(u128::from(u16::MAX)) as u16;
+            writer
+                .set_frame_delay(millis, 1000)
+                .map_err(|_| RecordError::EncodeError)?;
+            writer
+                .write_image_data(frame.image.as_raw())
+                .map_err(|_| RecordError::EncodeError)?;
+        }
+        writer.finish().map_err(|_| RecordError::EncodeError)?;
+    }
+    Ok(data)
+}
+
+fn webp(frames: &[Frame]) -> Result<Vec<u8>, RecordError> {
+    let (w, h) = frames[0].image.dimensions();
+    let mut encoder = webp_animation::Encoder::new((w, h)).map_err(|_| RecordError::EncodeError)?;
+    // Milliseconds since the start of the recording.
+    let mut timestamp = 0;
+    for frame in frames {
+        encoder
+            .add_frame(frame.image.as_raw(), timestamp)
+            .map_err(|_| RecordError::EncodeError)?;
+        timestamp += frame.delay.as_millis() as i32;
+    }
+    let data = encoder
+        .finalize(timestamp)
+        .map_err(|_| RecordError::EncodeError)?;
+    Ok(data.to_vec())
+}
+
+/// Encodes `frames` in `format`, one of `FORMATS`, returning the data and
+/// the extension to save it with. Animated PNGs keep the `png` extension.
+pub fn encode(frames: &[Frame], format: &str) -> Result<(Vec<u8>, &'static str), RecordError> {
+    match format {
+        "gif" => Ok((gif(frames)?, "gif")),
+        "apng" => Ok((apng(frames)?, "png")),
+        "webp" => Ok((webp(frames)?, "webp")),
+        _ => Err(RecordError::EncodeError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::img;
+
+    fn frames() -> Vec<Frame> {
+        [[255, 0, 0, 255], [0, 0, 255, 255]]
+            .iter()
+            .map(|color| Frame {
+                image: RgbaImage::from_pixel(8, 4, image::Rgba(*color)),
+                delay: Duration::from_millis(100),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn identical_frames_are_merged() {
+        let start = Instant::now();
+        let interval = Duration::from_millis(100);
+        let mut recording = Recording::new(interval, start);
+        let mut images = frames().into_iter().map(|frame| frame.image);
+        let (red, blue) = (images.next().unwrap(), images.next().unwrap());
+        let at = |millis| start + Duration::from_millis(millis);
+        assert!(recording.push(red.clone(), at(0)));
+        assert!(recording.push(red.clone(), at(100)));
+        assert!(recording.push(red, at(200)));
+        assert!(recording.push(blue.clone(), at(300)));
+        assert!(recording.push(blue, at(400)));
+        let recorded = recording.finish(at(450)).unwrap();
+        let delays: Vec<u128> = recorded
+            .iter()
+            .map(|frame| frame.delay.as_millis())
+            .collect();
+        // Red until blue was first captured, blue until the recording stopped.
+        assert_eq!(delays, vec![300, 150]);
+    }
+
+    #[test]
+    fn the_last_frame_is_shown_for_at_least_an_interval() {
+        let start = Instant::now();
+        let mut recording = Recording::new(Duration::from_millis(100), start);
+        recording.push(frames().remove(0).image, start);
+        let recorded = recording.finish(start + Duration::from_millis(10)).unwrap();
+        assert_eq!(recorded[0].delay, Duration::from_millis(100));
+        let empty = Recording::new(Duration::from_millis(100), start);
+        assert!(matches!(
+            empty.finish(start),
+            Err(RecordError::NothingRecorded)
+        ));
+    }
+
+    #[test]
+    fn recordings_encode_as_animations() {
+        for format in FORMATS.iter() {
+            let (data, extension) = encode(&frames(), format).unwrap();
+            assert_eq!(img::extension(&data), Some(extension));
+            assert_eq!(
+                img::frames(&data, extension),
+                img::Frames::Animated(8, 4),
+                "{}",
+                format
+            );
+        }
+    }
+}
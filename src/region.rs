@@ -1,13 +1,213 @@
+use crate::datafile;
+use crate::font;
 use crate::piston::Window;
+use crate::windows::{self, WindowInfo};
 use glutin_window::GlutinWindow;
 use graphics::draw_state::DrawState;
 use graphics::*;
-use opengl_graphics::{GlGraphics, OpenGL, Texture, TextureSettings};
+use opengl_graphics::{Filter, GlGraphics, OpenGL, Texture, TextureSettings};
 use piston::event_loop::{EventSettings, Events};
 use piston::input::*;
 use piston::input::{RenderArgs, RenderEvent};
-use piston::window::WindowSettings;
-use std::path::Path;
+use piston::window::{AdvancedWindow, WindowSettings};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const COLOR: [f32; 4] = [1.0, 1.0, 0.0, 0.1];
+const HANDLE_COLOR: [f32; 4] = [1.0, 1.0, 0.0, 0.8];
+/// Half the side of the squares that resize the selection.
+const HANDLE: f64 = 5.0;
+/// Pixels an arrow key moves the selection by, or with Shift held.
+const NUDGE: f64 = 1.0;
+const SHIFT_NUDGE: f64 = 10.0;
+
+/// Which edges a resize handle moves: -1 for left/top, 1 for right/bottom
+/// and 0 for neither.
+type Handle = (i8, i8);
+
+/// The picker's or the editor's window couldn't be shown.
+#[derive(Debug)]
+pub enum GuiError {
+    WindowError(String),
+    TextureError(String),
+}
+
+impl fmt::Display for GuiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GuiError::WindowError(e) => write!(f, "Couldn't open a window: {}", e),
+            GuiError::TextureError(e) => write!(f, "Couldn't show the screenshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for GuiError {}
+
+/// What dragging the mouse currently does.
+enum Drag {
+    Idle,
+    /// Drawing a new selection from a fixed corner.
+    Drawing([f64; 2]),
+    /// Moving the selection, grabbed this far from its top left corner.
+    Moving([f64; 2]),
+    /// Resizing the selection as it was when the handle was grabbed.
+    Resizing(Handle, [f64; 4]),
+}
+
+/// Rectangle between two corners, whichever way round they are.
+pub(crate) fn normalized(a: [f64; 2], b: [f64; 2]) -> [f64; 4] {
+    [
+        a[0].min(b[0]),
+        a[1].min(b[1]),
+        (a[0] - b[0]).abs(),
+        (a[1] - b[1]).abs(),
+    ]
+}
+
+/// Start and length of the span between a fixed and a moving edge.
+fn span(fixed: f64, moving: f64) -> (f64, f64) {
+    (fixed.min(moving), (fixed - moving).abs())
+}
+
+fn resize(rect: [f64; 4], handle: Handle, pos: [f64; 2]) -> [f64; 4] {
+    let (x, w) = match handle.0 {
+        -1 => span(rect[0] + rect[2], pos[0]),
+        1 => span(rect[0], pos[0]),
+        _ => (rect[0], rect[2]),
+    };
+    let (y, h) = match handle.1 {
+        -1 => span(rect[1] + rect[3], pos[1]),
+        1 => span(rect[1], pos[1]),
+        _ => (rect[1], rect[3]),
+    };
+    [x, y, w, h]
+}
+
+/// Centers of the eight resize handles of `rect`.
+fn handles(rect: [f64; 4]) -> Vec<(Handle, [f64; 2])> {
+    let mut handles = Vec::with_capacity(8);
+    for &hx in &[-1i8, 0, 1] {
+        for &hy in &[-1i8, 0, 1] {
+            if hx == 0 && hy == 0 {
+                continue;
+            }
+            let x = rect[0] + rect[2] * f64::from(hx + 1) / 2.0;
+            let y = rect[1] + rect[3] * f64::from(hy + 1) / 2.0;
+            handles.push(((hx, hy), [x, y]));
+        }
+    }
+    handles
+}
+
+fn handle_at(rect: [f64; 4], pos: [f64; 2]) -> Option<Handle> {
+    handles(rect)
+        .into_iter()
+        .find(|(_, center)| {
+            (pos[0] - center[0]).abs() <= HANDLE && (pos[1] - center[1]).abs() <= HANDLE
+        })
+        .map(|(handle, _)| handle)
+}
+
+/// The selection if it covers at least a pixel, which is all Enter confirms.
+fn selected(selection: Option<[f64; 4]>) -> Option<[f64; 4]> {
+    selection.filter(|rect| rect[2] >= 1.0 && rect[3] >= 1.0)
+}
+
+fn contains(rect: [f64; 4], pos: [f64; 2]) -> bool {
+    pos[0] >= rect[0]
+        && pos[0] <= rect[0] + rect[2]
+        && pos[1] >= rect[1]
+        && pos[1] <= rect[1] + rect[3]
+}
+
+/// Part of `rect` inside a `size` screen, if any.
+pub fn clip(rect: [f64; 4], size: [f64; 2]) -> Option<[f64; 4]> {
+    let x = rect[0].max(0.0);
+    let y = rect[1].max(0.0);
+    let w = (rect[0] + rect[2]).min(size[0]) - x;
+    let h = (rect[1] + rect[3]).min(size[1]) - y;
+    if w < 1.0 || h < 1.0 {
+        return None;
+    }
+    Some([x, y, w, h])
+}
+
+/// Keeps `rect` inside a `size` screen without changing its size.
+fn clamp(rect: [f64; 4], size: [f64; 2]) -> [f64; 4] {
+    let x = rect[0].max(0.0).min((size[0] - rect[2]).max(0.0));
+    let y = rect[1].max(0.0).min((size[1] - rect[3]).max(0.0));
+    [x, y, rect[2], rect[3]]
+}
+
+/// Darkens everything outside the selection.
+const DIM: [f32; 4] = [0.0, 0.0, 0.0, 0.4];
+const CROSSHAIR: [f32; 4] = [1.0, 1.0, 0.0, 0.5];
+const LABEL: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const LABEL_BACKGROUND: [f32; 4] = [0.0, 0.0, 0.0, 0.7];
+const LABEL_SCALE: f64 = 2.0;
+/// Screen pixels around the cursor shown in the loupe, on each side.
+const LOUPE_RADIUS: f64 = 8.0;
+const LOUPE_ZOOM: f64 = 8.0;
+/// Gap between the cursor and the loupe or label.
+const OFFSET: f64 = 20.0;
+
+/// Everything the picker draws on top of the screenshot.
+struct Scene {
+    screen: [f64; 2],
+    cursor: [f64; 2],
+    selection: Option<[f64; 4]>,
+    /// Window that clicking would select.
+    hovered: Option<[f64; 4]>,
+}
+
+/// Width of `text` as drawn by `text`, background included.
+pub(crate) fn text_width(text: &str) -> f64 {
+    f64::from(font::width(text)) * LABEL_SCALE + LABEL_SCALE * 4.0
+}
+
+/// Draws `text` with the bitmap font, top left corner at `pos`.
+pub(crate) fn text<G: Graphics>(text: &str, pos: [f64; 2], transform: math::Matrix2d, gl: &mut G) {
+    let w = f64::from(font::width(text)) * LABEL_SCALE;
+    let h = f64::from(font::HEIGHT) * LABEL_SCALE;
+    let padding = LABEL_SCALE * 2.0;
+    rectangle(
+        LABEL_BACKGROUND,
+        [pos[0], pos[1], w + padding * 2.0, h + padding * 2.0],
+        transform,
+        gl,
+    );
+    for (x, y) in font::pixels(text) {
+        rectangle(
+            LABEL,
+            [
+                pos[0] + padding + f64::from(x) * LABEL_SCALE,
+                pos[1] + padding + f64::from(y) * LABEL_SCALE,
+                LABEL_SCALE,
+                LABEL_SCALE,
+            ],
+            transform,
+            gl,
+        );
+    }
+}
+
+/// Where to put something `size` big next to the cursor, flipped to the
+/// other side when it would go off screen.
+fn beside(cursor: [f64; 2], size: [f64; 2], screen: [f64; 2]) -> [f64; 2] {
+    let x = if cursor[0] + OFFSET + size[0] > screen[0] {
+        cursor[0] - OFFSET - size[0]
+    } else {
+        cursor[0] + OFFSET
+    };
+    let y = if cursor[1] + OFFSET + size[1] > screen[1] {
+        cursor[1] - OFFSET - size[1]
+    } else {
+        cursor[1] + OFFSET
+    };
+    [x.max(0.0), y.max(0.0)]
+}
 
 pub struct App {
     gl: GlGraphics, // OpenGL drawing backend.
@@ -19,66 +219,170 @@ impl App {
         image: &Image,
         texture: &Texture,
         &rect: &rectangle::Rectangle,
-        &initial_cursor_pos: &[f64; 2],
-        &cursor_pos: &[f64; 2],
-        &initial_cursor_pos_set: &bool,
+        scene: &Scene,
         &draw_state: &DrawState,
     ) {
         self.gl.draw(args.viewport(), |c, gl| {
             image.draw(texture, &draw_state, c.transform, gl);
-            if !initial_cursor_pos_set {
-                return;
-            };
-            rect.draw(
+            let [screen_w, screen_h] = scene.screen;
+            let [cursor_x, cursor_y] = scene.cursor;
+
+            match scene.selection {
+                Some([x, y, w, h]) => {
+                    rectangle(DIM, [0.0, 0.0, screen_w, y], c.transform, gl);
+                    rectangle(
+                        DIM,
+                        [0.0, y + h, screen_w, screen_h - y - h],
+                        c.transform,
+                        gl,
+                    );
+                    rectangle(DIM, [0.0, y, x, h], c.transform, gl);
+                    rectangle(DIM, [x + w, y, screen_w - x - w, h], c.transform, gl);
+                    rect.draw([x, y, w, h], &draw_state, c.transform, gl);
+                    for (_, center) in handles([x, y, w, h]) {
+                        rectangle(
+                            HANDLE_COLOR,
+                            rectangle::centered_square(center[0], center[1], HANDLE),
+                            c.transform,
+                            gl,
+                        );
+                    }
+                }
+                None => rectangle(DIM, [0.0, 0.0, screen_w, screen_h], c.transform, gl),
+            }
+            if let Some(hovered) = scene.hovered {
+                Rectangle::new_border(HANDLE_COLOR, 1.5).draw(
+                    hovered,
+                    &draw_state,
+                    c.transform,
+                    gl,
+                );
+            }
+
+            line(
+                CROSSHAIR,
+                0.5,
+                [0.0, cursor_y, screen_w, cursor_y],
+                c.transform,
+                gl,
+            );
+            line(
+                CROSSHAIR,
+                0.5,
+                [cursor_x, 0.0, cursor_x, screen_h],
+                c.transform,
+                gl,
+            );
+
+            // Loupe: the pixels around the cursor, scaled up without smoothing.
+            let loupe_size = LOUPE_RADIUS * 2.0 * LOUPE_ZOOM;
+            let [loupe_x, loupe_y] = beside(scene.cursor, [loupe_size, loupe_size], scene.screen);
+            Image::new()
+                .src_rect([
+                    cursor_x.floor() - LOUPE_RADIUS,
+                    cursor_y.floor() - LOUPE_RADIUS,
+                    LOUPE_RADIUS * 2.0,
+                    LOUPE_RADIUS * 2.0,
+                ])
+                .rect([loupe_x, loupe_y, loupe_size, loupe_size])
+                .draw(texture, &draw_state, c.transform, gl);
+            Rectangle::new_border(CROSSHAIR, 1.0).draw(
+                [loupe_x, loupe_y, loupe_size, loupe_size],
+                &draw_state,
+                c.transform,
+                gl,
+            );
+            // Outline of the pixel under the cursor.
+            Rectangle::new_border(HANDLE_COLOR, 1.0).draw(
                 [
-                    initial_cursor_pos[0],
-                    initial_cursor_pos[1],
-                    cursor_pos[0] - initial_cursor_pos[0],
-                    cursor_pos[1] - initial_cursor_pos[1],
+                    loupe_x + LOUPE_RADIUS * LOUPE_ZOOM,
+                    loupe_y + LOUPE_RADIUS * LOUPE_ZOOM,
+                    LOUPE_ZOOM,
+                    LOUPE_ZOOM,
                 ],
                 &draw_state,
                 c.transform,
                 gl,
             );
+
+            let label = match scene.selection {
+                Some([x, y, w, h]) => {
+                    format!("{},{} {}×{}", x.round(), y.round(), w.round(), h.round())
+                }
+                None => format!("{},{}", cursor_x.round(), cursor_y.round()),
+            };
+            let label_pos = match scene.selection {
+                // Just above the selection, or inside it at the top of the screen.
+                Some([x, y, _, _]) => {
+                    let height = (f64::from(font::HEIGHT) + 4.0) * LABEL_SCALE;
+                    [x, if y >= height { y - height } else { y }]
+                }
+                None => [cursor_x + OFFSET, (cursor_y - OFFSET * 2.0).max(0.0)],
+            };
+            text(&label, label_pos, c.transform, gl);
         });
     }
 }
 
-pub fn get_region(screenshot_path: &str) -> Option<[f64; 4]> {
+/// Lets the user pick a region of the screenshot at `screenshot_path`. When
+/// it `spans` several displays the picker covers all of them instead of
+/// going fullscreen on one.
+///
+/// A selection can be drawn from any corner, then moved by dragging it,
+/// resized from its handles or nudged with the arrow keys (Shift for bigger
+/// steps) until Enter confirms it. Clicking without dragging selects the
+/// topmost of the `snap_to` windows under the cursor, given from bottom to top.
+///
+/// Returns `None` if the picker was closed without confirming a selection.
+pub fn get_region(
+    screenshot_path: &str,
+    spans: bool,
+    snap_to: &[WindowInfo],
+) -> Result<Option<[f64; 4]>, GuiError> {
     // Change this to OpenGL::V2_1 if not working.
     let opengl = OpenGL::V3_2;
 
+    let size = if spans {
+        ::image::image_dimensions(screenshot_path).unwrap_or((200, 200))
+    } else {
+        (200, 200)
+    };
     // Create an Glutin window.
-    let mut window: GlutinWindow = WindowSettings::new("sampic", [200, 200])
-        .fullscreen(true)
+    let mut window: GlutinWindow = WindowSettings::new("sampic", [size.0, size.1])
+        .fullscreen(!spans)
         .decorated(false)
         .graphics_api(opengl)
         .exit_on_esc(true)
         .samples(2)
         .build()
-        .unwrap();
+        .map_err(|e| GuiError::WindowError(e.to_string()))?;
+    if spans {
+        window.set_position([0, 0]);
+    }
 
     // Create a new game and run it.
     let mut app = App {
         gl: GlGraphics::new(opengl),
     };
 
-    let texture_settings = TextureSettings::new();
+    // Nearest neighbour scaling keeps pixels sharp in the loupe.
+    let texture_settings = TextureSettings::new().filter(Filter::Nearest);
     let image = Image::new();
     //A texture to use with the image
-    let texture = Texture::from_path(
-        Path::new(&screenshot_path),
-        &texture_settings,
-    )
-    .unwrap();
+    let texture = Texture::from_path(Path::new(&screenshot_path), &texture_settings)
+        .map_err(GuiError::TextureError)?;
+    let (texture_w, texture_h) = texture.get_size();
+    let screen = [f64::from(texture_w), f64::from(texture_h)];
     let draw_state = &DrawState::new_alpha();
 
     let mut events = Events::new(EventSettings::new());
-    let mut initial_cursor_pos = [0.0, 0.0];
-    let mut initial_cursor_pos_set = false;
-    let mut cursor_pos = [1.0, 1.0];
-
-    const COLOR: [f32; 4] = [1.0, 1.0, 0.0, 0.1];
+    let mut cursor_pos = [0.0, 0.0];
+    let mut selection: Option<[f64; 4]> = None;
+    let mut drag = Drag::Idle;
+    let mut shift = false;
+    // Only set once Enter confirms the selection, so closing the window any
+    // other way (Esc, the window manager) cancels.
+    let mut confirmed = false;
 
     let rect = rectangle::Rectangle::new(COLOR).border(rectangle::Border {
         color: COLOR,
@@ -86,47 +390,208 @@ pub fn get_region(screenshot_path: &str) -> Option<[f64; 4]> {
     });
     while let Some(e) = events.next(&mut window) {
         e.mouse_cursor(|pos| {
-            if initial_cursor_pos_set {
-                let x: f64;
-                let y: f64;
-                if pos[0] > initial_cursor_pos[0] {
-                    x = pos[0];
-                } else {
-                    x = initial_cursor_pos[0];
-                };
-                if pos[1] > initial_cursor_pos[1] {
-                    y = pos[1];
-                } else {
-                    y = initial_cursor_pos[1];
-                };
-                cursor_pos = [x, y];
-            } else {
-                initial_cursor_pos = pos;
+            cursor_pos = [
+                pos[0].max(0.0).min(screen[0]),
+                pos[1].max(0.0).min(screen[1]),
+            ];
+            selection = match drag {
+                Drag::Idle => selection,
+                Drag::Drawing(anchor) => Some(normalized(anchor, cursor_pos)),
+                Drag::Moving(grab) => selection.map(|selection| {
+                    clamp(
+                        [
+                            cursor_pos[0] - grab[0],
+                            cursor_pos[1] - grab[1],
+                            selection[2],
+                            selection[3],
+                        ],
+                        screen,
+                    )
+                }),
+                Drag::Resizing(handle, original) => Some(resize(original, handle, cursor_pos)),
             };
         });
-        if let Some(Button::Mouse(_)) = e.press_args() {
-            initial_cursor_pos_set = true;
+        match e.press_args() {
+            Some(Button::Mouse(MouseButton::Left)) => {
+                drag = match selection {
+                    Some(current) => match handle_at(current, cursor_pos) {
+                        Some(handle) => Drag::Resizing(handle, current),
+                        None if contains(current, cursor_pos) => {
+                            Drag::Moving([cursor_pos[0] - current[0], cursor_pos[1] - current[1]])
+                        }
+                        None => Drag::Drawing(cursor_pos),
+                    },
+                    None => Drag::Drawing(cursor_pos),
+                };
+                if let Drag::Drawing(anchor) = drag {
+                    selection = Some(normalized(anchor, anchor));
+                }
+            }
+            Some(Button::Keyboard(Key::LShift)) | Some(Button::Keyboard(Key::RShift)) => {
+                shift = true;
+            }
+            Some(Button::Keyboard(key)) => {
+                let step = if shift { SHIFT_NUDGE } else { NUDGE };
+                let offset = match key {
+                    Key::Left => Some([-step, 0.0]),
+                    Key::Right => Some([step, 0.0]),
+                    Key::Up => Some([0.0, -step]),
+                    Key::Down => Some([0.0, step]),
+                    _ => None,
+                };
+                if let (Some(offset), Some(current)) = (offset, selection) {
+                    selection = Some(clamp(
+                        [
+                            current[0] + offset[0],
+                            current[1] + offset[1],
+                            current[2],
+                            current[3],
+                        ],
+                        screen,
+                    ));
+                }
+                // Nothing to confirm yet, Esc is how to leave without one.
+                let enter = key == Key::Return || key == Key::NumPadEnter;
+                if enter && selected(selection).is_some() {
+                    confirmed = true;
+                    window.set_should_close(true);
+                }
+            }
+            _ => {}
         };
-        if let Some(Button::Mouse(_)) = e.release_args() {
-            window.set_should_close(true);
+        match e.release_args() {
+            Some(Button::Mouse(MouseButton::Left)) => {
+                if let Drag::Drawing(_) = drag {
+                    let clicked =
+                        selection.map_or(true, |current| current[2] < 1.0 || current[3] < 1.0);
+                    if clicked {
+                        selection = windows::at(snap_to, cursor_pos)
+                            .and_then(|window| clip(window.rect, screen));
+                    }
+                }
+                drag = Drag::Idle;
+            }
+            Some(Button::Keyboard(Key::LShift)) | Some(Button::Keyboard(Key::RShift)) => {
+                shift = false;
+            }
+            _ => {}
         };
         if let Some(args) = e.render_args() {
-            app.render(
-                &args,
-                &image,
-                &texture,
-                &rect,
-                &initial_cursor_pos,
-                &cursor_pos,
-                &initial_cursor_pos_set,
-                &draw_state,
-            );
+            let hovered = match (&drag, selection) {
+                (Drag::Idle, None) => {
+                    windows::at(snap_to, cursor_pos).and_then(|window| clip(window.rect, screen))
+                }
+                _ => None,
+            };
+            let scene = Scene {
+                screen,
+                cursor: cursor_pos,
+                selection,
+                hovered,
+            };
+            app.render(&args, &image, &texture, &rect, &scene, &draw_state);
         }
     }
-    return Some([
-        initial_cursor_pos[0],
-        initial_cursor_pos[1],
-        cursor_pos[0] - initial_cursor_pos[0],
-        cursor_pos[1] - initial_cursor_pos[1],
-    ]);
+    if !confirmed {
+        return Ok(None);
+    }
+    Ok(selected(selection))
+}
+
+#[derive(Serialize, Deserialize)]
+struct LastRegion {
+    region: [f64; 4],
+}
+
+fn last_region_path() -> Option<PathBuf> {
+    let dirs = datafile::dirs().ok()?;
+    Some(dirs.data_dir().join("last_region.toml"))
+}
+
+/// Region used by the previous screenshot.
+pub fn last_region() -> Option<[f64; 4]> {
+    let contents = fs::read_to_string(last_region_path()?).ok()?;
+    let last: LastRegion = toml::from_str(&contents).ok()?;
+    Some(last.region)
+}
+
+/// Saves `region` so the next screenshot can reuse it with `--last-region`.
+pub fn remember(region: [f64; 4]) {
+    if let Some(path) = last_region_path() {
+        datafile::save(&path, &LastRegion { region }).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selections_are_normalized_whichever_way_they_are_drawn() {
+        let expected = [10.0, 20.0, 30.0, 40.0];
+        assert_eq!(normalized([10.0, 20.0], [40.0, 60.0]), expected);
+        assert_eq!(normalized([40.0, 60.0], [10.0, 20.0]), expected);
+        assert_eq!(normalized([40.0, 20.0], [10.0, 60.0]), expected);
+        assert_eq!(normalized([10.0, 60.0], [40.0, 20.0]), expected);
+    }
+
+    #[test]
+    fn handles_resize_their_edges_in_every_direction() {
+        let rect = [10.0, 10.0, 20.0, 20.0];
+        assert_eq!(resize(rect, (1, 0), [50.0, 0.0]), [10.0, 10.0, 40.0, 20.0]);
+        assert_eq!(resize(rect, (-1, 0), [0.0, 0.0]), [0.0, 10.0, 30.0, 20.0]);
+        assert_eq!(resize(rect, (0, 1), [0.0, 40.0]), [10.0, 10.0, 20.0, 30.0]);
+        assert_eq!(resize(rect, (0, -1), [0.0, 5.0]), [10.0, 5.0, 20.0, 25.0]);
+        assert_eq!(resize(rect, (1, 1), [15.0, 15.0]), [10.0, 10.0, 5.0, 5.0]);
+        // Dragging an edge past the opposite one flips the selection.
+        assert_eq!(
+            resize(rect, (-1, -1), [40.0, 50.0]),
+            [30.0, 30.0, 10.0, 20.0]
+        );
+        assert_eq!(resize(rect, (1, 0), [4.0, 0.0]), [4.0, 10.0, 6.0, 20.0]);
+    }
+
+    #[test]
+    fn moved_selections_stay_on_screen() {
+        let screen = [100.0, 50.0];
+        assert_eq!(
+            clamp([-5.0, -5.0, 10.0, 10.0], screen),
+            [0.0, 0.0, 10.0, 10.0]
+        );
+        assert_eq!(
+            clamp([95.0, 45.0, 10.0, 10.0], screen),
+            [90.0, 40.0, 10.0, 10.0]
+        );
+        assert_eq!(
+            clamp([20.0, 20.0, 10.0, 10.0], screen),
+            [20.0, 20.0, 10.0, 10.0]
+        );
+        // Too large to fit, it's kept at the top left corner.
+        assert_eq!(
+            clamp([20.0, 20.0, 200.0, 80.0], screen),
+            [0.0, 0.0, 200.0, 80.0]
+        );
+    }
+
+    #[test]
+    fn handles_are_grabbed_around_corners_and_edge_middles() {
+        let rect = [10.0, 10.0, 20.0, 20.0];
+        assert_eq!(handle_at(rect, [10.0, 10.0]), Some((-1, -1)));
+        assert_eq!(handle_at(rect, [30.0 + HANDLE, 30.0]), Some((1, 1)));
+        assert_eq!(handle_at(rect, [20.0, 10.0]), Some((0, -1)));
+        assert_eq!(handle_at(rect, [10.0, 20.0]), Some((-1, 0)));
+        assert_eq!(handle_at(rect, [20.0, 20.0]), None);
+        assert_eq!(handle_at(rect, [30.0 + HANDLE + 1.0, 30.0]), None);
+    }
+
+    #[test]
+    fn only_selections_of_a_pixel_or_more_can_be_confirmed() {
+        assert_eq!(selected(None), None);
+        assert_eq!(selected(Some([5.0, 5.0, 0.0, 0.0])), None);
+        assert_eq!(selected(Some([5.0, 5.0, 10.0, 0.5])), None);
+        assert_eq!(
+            selected(Some([5.0, 5.0, 1.0, 1.0])),
+            Some([5.0, 5.0, 1.0, 1.0])
+        );
+    }
 }
@@ -0,0 +1,175 @@
+//! What is copied to the clipboard once an image is saved: its link, a
+//! snippet embedding it for wherever it's pasted, or the image itself.
+
+/// Accepted values for `clipboard`.
+pub const ACTIONS: [&str; 6] = ["url", "markdown", "html", "bbcode", "template", "image"];
+
+/// The saved image, as far as snippets are concerned.
+pub struct Details<'a> {
+    pub url: &'a str,
+    pub name: &'a str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Percent-encodes the brackets in a link, which BBCode can't escape, so it
+/// can't close the tag early.
+fn bracketless(url: &str) -> String {
+    url.replace('[', "%5B").replace(']', "%5D")
+}
+
+/// Markdown image of `url` with `alt` text, escaped so neither can end early.
+fn markdown(alt: &str, url: &str) -> String {
+    let alt = alt
+        .replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]");
+    let url = url
+        .replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29");
+    format!("![{}]({})", alt, url)
+}
+
+/// Fills the placeholders in `template` in a single pass, so values that
+/// happen to contain one are left as they are.
+fn fill(template: &str, details: &Details) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let placeholder = rest.find('}').and_then(|end| {
+            let value = match &rest[1..end] {
+                "url" => details.url.to_string(),
+                "name" => details.name.to_string(),
+                "width" => details.width.to_string(),
+                "height" => details.height.to_string(),
+                _ => return None,
+            };
+            Some((value, end))
+        });
+        match placeholder {
+            Some((value, end)) => {
+                filled.push_str(&value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Text copied for `action`, with `{url}`, `{name}`, `{width}` and
+/// `{height}` in `template` filled in for the `template` action. `None` for
+/// `image`, which copies the image instead of text.
+pub fn text(action: &str, template: &str, details: &Details) -> Option<String> {
+    let url = details.url;
+    match action {
+        "image" => None,
+        "markdown" => Some(markdown(details.name, url)),
+        "html" => Some(format!(
+            "<img src=\"{}\" width=\"{}\" height=\"{}\">",
+            escape(url),
+            details.width,
+            details.height
+        )),
+        "bbcode" => Some(format!("[img]{}[/img]", bracketless(url))),
+        "template" => Some(fill(template, details)),
+        _ => Some(url.to_string()),
+    }
+}
+
+/// How the notification refers to what was copied.
+pub fn description(action: &str) -> &'static str {
+    match action {
+        "image" => "image",
+        "markdown" => "Markdown",
+        "html" => "HTML",
+        "bbcode" => "BBCode",
+        "template" => "snippet",
+        _ => "URL",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets_embed_the_link() {
+        let details = Details {
+            url: "https://sampic.xyz/a.png",
+            name: "a.png",
+            width: 640,
+            height: 480,
+        };
+        assert_eq!(
+            text("markdown", "", &details).unwrap(),
+            "![a.png](https://sampic.xyz/a.png)"
+        );
+        assert_eq!(
+            text("template", "{url} ({width}x{height})", &details).unwrap(),
+            "https://sampic.xyz/a.png (640x480)"
+        );
+        assert!(text("image", "", &details).is_none());
+    }
+
+    #[test]
+    fn links_cant_break_out_of_html_or_bbcode() {
+        let details = Details {
+            url: "https://sampic.xyz/a.png?q=\"><script>&[/img][url]x",
+            name: "a.png",
+            width: 1,
+            height: 2,
+        };
+        assert_eq!(
+            text("html", "", &details).unwrap(),
+            "<img src=\"https://sampic.xyz/a.png?q=&quot;&gt;&lt;script&gt;&amp;[/img][url]x\" \
+             width=\"1\" height=\"2\">"
+        );
+        assert_eq!(
+            text("bbcode", "", &details).unwrap(),
+            "[img]https://sampic.xyz/a.png?q=\"><script>&%5B/img%5D%5Burl%5Dx[/img]"
+        );
+    }
+
+    #[test]
+    fn markdown_links_and_alt_text_are_escaped() {
+        let details = Details {
+            url: "https://sampic.xyz/my shot (1).png",
+            name: "a] [b\\",
+            width: 1,
+            height: 2,
+        };
+        assert_eq!(
+            text("markdown", "", &details).unwrap(),
+            "![a\\] \\[b\\\\](https://sampic.xyz/my%20shot%20%281%29.png)"
+        );
+    }
+
+    #[test]
+    fn templates_are_filled_once() {
+        let details = Details {
+            url: "https://sampic.xyz/{name}.png",
+            name: "{width}",
+            width: 640,
+            height: 480,
+        };
+        assert_eq!(
+            text("template", "{url} {name} {unknown} {height}{", &details).unwrap(),
+            "https://sampic.xyz/{name}.png {width} {unknown} 480{"
+        );
+    }
+}
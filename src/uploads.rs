@@ -0,0 +1,92 @@
+//! Record of what the server stored, who uploaded it and the token that
+//! deletes it. Kept in `uploads.toml` in sampic's data directory.
+use super::datafile::{self, DataError};
+use super::keys::random_token;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Upload {
+    pub delete_token: String,
+    pub key: String,
+    pub ip: Option<String>,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct UploadTable {
+    uploads: HashMap<String, Upload>,
+}
+
+pub struct Uploads {
+    path: Option<PathBuf>,
+    table: Mutex<UploadTable>,
+}
+
+impl Uploads {
+    /// Ledger kept in sampic's data directory.
+    pub fn open() -> Result<Self, DataError> {
+        Uploads::at(Some(datafile::data_dir()?.join("uploads.toml")))
+    }
+
+    /// Ledger kept at `path`, or only in memory if `None`.
+    pub fn at(path: Option<PathBuf>) -> Result<Self, DataError> {
+        let table = match &path {
+            Some(path) => datafile::load(path)?,
+            None => UploadTable::default(),
+        };
+        Ok(Uploads {
+            path,
+            table: Mutex::new(table),
+        })
+    }
+
+    fn persist(&self, table: &UploadTable) -> Result<(), DataError> {
+        match &self.path {
+            Some(path) => datafile::save(path, table),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a stored upload and returns the token that deletes it. An object
+    /// that was already recorded keeps its first uploader, token and size, and
+    /// `None` is returned.
+    pub fn record(
+        &self,
+        name: &str,
+        key: &str,
+        ip: Option<String>,
+        size: u64,
+    ) -> Result<Option<String>, DataError> {
+        let mut table = self.table.lock().unwrap();
+        if table.uploads.contains_key(name) {
+            return Ok(None);
+        }
+        let delete_token = random_token(TOKEN_LENGTH);
+        table.uploads.insert(
+            name.to_string(),
+            Upload {
+                delete_token: delete_token.clone(),
+                key: key.to_string(),
+                ip,
+                size,
+            },
+        );
+        self.persist(&table)?;
+        Ok(Some(delete_token))
+    }
+
+    pub fn find(&self, name: &str) -> Option<Upload> {
+        self.table.lock().unwrap().uploads.get(name).cloned()
+    }
+
+    pub fn forget(&self, name: &str) -> Result<(), DataError> {
+        let mut table = self.table.lock().unwrap();
+        table.uploads.remove(name);
+        self.persist(&table)
+    }
+}
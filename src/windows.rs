@@ -0,0 +1,247 @@
+//! Geometry of the application windows on screen, used to snap selections to
+//! them, and of the monitors they're shown on. Only X11 is supported; both
+//! are read from the same X server scrap captures.
+use std::fmt;
+
+#[derive(Debug)]
+pub enum WindowError {
+    ConnectError,
+    RequestError,
+    NotFound,
+    NoMonitor,
+    Unsupported,
+}
+
+impl fmt::Display for WindowError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WindowError::ConnectError => write!(f, "Couldn't connect to the X server"),
+            WindowError::RequestError => write!(f, "X server request failed"),
+            WindowError::NotFound => write!(f, "No window with that id or name"),
+            WindowError::NoMonitor => write!(f, "No display with that name"),
+            WindowError::Unsupported => write!(f, "Window selection needs X11"),
+        }
+    }
+}
+
+impl std::error::Error for WindowError {}
+
+#[derive(Clone, Debug)]
+pub struct WindowInfo {
+    pub id: u32,
+    pub name: String,
+    /// `[x, y, w, h]` relative to the root window.
+    pub rect: [f64; 4],
+}
+
+impl WindowInfo {
+    fn contains(&self, pos: [f64; 2]) -> bool {
+        let [x, y, w, h] = self.rect;
+        pos[0] >= x && pos[0] < x + w && pos[1] >= y && pos[1] < y + h
+    }
+
+    /// The window with its position relative to `origin` instead of the
+    /// root window, e.g. to the top left corner of a screenshot.
+    pub fn relative_to(mut self, origin: [f64; 2]) -> Self {
+        self.rect[0] -= origin[0];
+        self.rect[1] -= origin[1];
+        self
+    }
+}
+
+/// A monitor, as RandR reports it.
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    /// Output name, e.g. `HDMI-1`.
+    pub name: String,
+    pub primary: bool,
+    /// `[x, y, w, h]` relative to the root window.
+    pub rect: [f64; 4],
+}
+
+/// Topmost window under `pos`, given windows from bottom to top.
+pub fn at(windows: &[WindowInfo], pos: [f64; 2]) -> Option<&WindowInfo> {
+    windows.iter().rev().find(|window| window.contains(pos))
+}
+
+/// Window whose id (decimal or `0x` hexadecimal) is `query`, or else the
+/// topmost one whose name contains it.
+pub fn find(query: &str) -> Result<WindowInfo, WindowError> {
+    let id = match query.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => query.parse().ok(),
+    };
+    let windows = list()?;
+    if let Some(id) = id {
+        if let Some(window) = windows.iter().find(|window| window.id == id) {
+            return Ok(window.clone());
+        }
+    }
+    windows
+        .into_iter()
+        .rev()
+        .find(|window| window.name.contains(query))
+        .ok_or(WindowError::NotFound)
+}
+
+#[cfg(target_os = "linux")]
+mod x11 {
+    use super::{Monitor, WindowError, WindowInfo};
+    use x11rb::connection::Connection;
+    use x11rb::errors::{ConnectError, ConnectionError, ReplyError};
+    use x11rb::protocol::randr::ConnectionExt as _;
+    use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, MapState, Window};
+
+    impl From<ConnectError> for WindowError {
+        fn from(_: ConnectError) -> WindowError {
+            WindowError::ConnectError
+        }
+    }
+
+    impl From<ConnectionError> for WindowError {
+        fn from(_: ConnectionError) -> WindowError {
+            WindowError::RequestError
+        }
+    }
+
+    impl From<ReplyError> for WindowError {
+        fn from(_: ReplyError) -> WindowError {
+            WindowError::RequestError
+        }
+    }
+
+    fn atom(conn: &impl Connection, name: &[u8]) -> Result<u32, WindowError> {
+        Ok(conn.intern_atom(false, name)?.reply()?.atom)
+    }
+
+    /// Atoms needed to read window names, interned once per listing.
+    struct Names {
+        net_wm_name: u32,
+        utf8: u32,
+    }
+
+    fn name(conn: &impl Connection, names: &Names, window: Window) -> Result<String, ReplyError> {
+        let reply = conn
+            .get_property(false, window, names.net_wm_name, names.utf8, 0, 1024)?
+            .reply()?;
+        if !reply.value.is_empty() {
+            return Ok(String::from_utf8_lossy(&reply.value).into_owned());
+        }
+        let reply = conn
+            .get_property(false, window, AtomEnum::WM_NAME, AtomEnum::STRING, 0, 1024)?
+            .reply()?;
+        Ok(String::from_utf8_lossy(&reply.value).into_owned())
+    }
+
+    /// Windows in stacking order, bottom to top. Uses the window manager's
+    /// client list when there is one, so the names and bounds are those of
+    /// the applications rather than their frames.
+    fn stacking(conn: &impl Connection, root: Window) -> Result<Vec<Window>, WindowError> {
+        let client_list = atom(conn, b"_NET_CLIENT_LIST_STACKING")?;
+        let reply = conn
+            .get_property(false, root, client_list, AtomEnum::WINDOW, 0, u32::MAX)?
+            .reply()?;
+        let clients: Vec<Window> = reply.value32().map(|ids| ids.collect()).unwrap_or_default();
+        if !clients.is_empty() {
+            return Ok(clients);
+        }
+        Ok(conn.query_tree(root)?.reply()?.children)
+    }
+
+    /// `window` if it's visible.
+    fn info(
+        conn: &impl Connection,
+        root: Window,
+        names: &Names,
+        window: Window,
+    ) -> Result<Option<WindowInfo>, ReplyError> {
+        let attributes = conn.get_window_attributes(window)?.reply()?;
+        if attributes.map_state != MapState::VIEWABLE {
+            return Ok(None);
+        }
+        let geometry = conn.get_geometry(window)?.reply()?;
+        let origin = conn.translate_coordinates(window, root, 0, 0)?.reply()?;
+        Ok(Some(WindowInfo {
+            id: window,
+            name: name(conn, names, window)?,
+            rect: [
+                f64::from(origin.dst_x),
+                f64::from(origin.dst_y),
+                f64::from(geometry.width),
+                f64::from(geometry.height),
+            ],
+        }))
+    }
+
+    pub fn list() -> Result<Vec<WindowInfo>, WindowError> {
+        let (conn, screen) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        let names = Names {
+            net_wm_name: atom(&conn, b"_NET_WM_NAME")?,
+            utf8: atom(&conn, b"UTF8_STRING")?,
+        };
+        let mut windows = Vec::new();
+        for window in stacking(&conn, root)? {
+            match info(&conn, root, &names, window) {
+                Ok(Some(info)) => windows.push(info),
+                Ok(None) => {}
+                // Closed while the list was being read (BadWindow).
+                Err(ReplyError::X11Error(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(windows)
+    }
+
+    /// Active monitors, in the order RandR lists them.
+    pub fn monitors() -> Result<Vec<Monitor>, WindowError> {
+        let (conn, screen) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen].root;
+        let reply = conn.randr_get_monitors(root, true)?.reply()?;
+        let mut monitors = Vec::new();
+        for monitor in reply.monitors {
+            let name = conn.get_atom_name(monitor.name)?.reply()?.name;
+            monitors.push(Monitor {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                primary: monitor.primary,
+                rect: [
+                    f64::from(monitor.x),
+                    f64::from(monitor.y),
+                    f64::from(monitor.width),
+                    f64::from(monitor.height),
+                ],
+            });
+        }
+        Ok(monitors)
+    }
+}
+
+/// Visible top level windows from bottom to top.
+#[cfg(target_os = "linux")]
+pub fn list() -> Result<Vec<WindowInfo>, WindowError> {
+    x11::list()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn list() -> Result<Vec<WindowInfo>, WindowError> {
+    Err(WindowError::Unsupported)
+}
+
+/// Active monitors, as `--display` numbers them on X11.
+#[cfg(target_os = "linux")]
+pub fn monitors() -> Result<Vec<Monitor>, WindowError> {
+    x11::monitors()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn monitors() -> Result<Vec<Monitor>, WindowError> {
+    Err(WindowError::Unsupported)
+}
+
+/// Index, as `--display` takes it, of the monitor called `name`.
+pub fn monitor_index(name: &str) -> Result<usize, WindowError> {
+    monitors()?
+        .iter()
+        .position(|monitor| monitor.name == name)
+        .ok_or(WindowError::NoMonitor)
+}
@@ -0,0 +1,66 @@
+//! Takes real screenshots with the `sampic` binary, so it needs an X server
+//! with a true color screen, e.g.
+//! `xvfb-run -s "-screen 0 1024x768x24" cargo test --test capture -- --ignored`.
+#![cfg(target_os = "linux")]
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use x11rb::connection::Connection;
+
+/// Runs `sampic` with its configuration and data kept in `home`, and returns
+/// what it printed.
+fn sampic(home: &Path, args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_sampic"))
+        .env("HOME", home)
+        .env("XDG_CONFIG_HOME", home.join("config"))
+        .env("XDG_DATA_HOME", home.join("data"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "sampic {}: {}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+fn size(path: &str) -> (u32, u32) {
+    image::image_dimensions(path).unwrap()
+}
+
+#[test]
+#[ignore]
+fn regions_are_captured_without_the_selector() {
+    let (conn, screen) = x11rb::connect(None).expect("an X server, try xvfb-run");
+    let screen = &conn.setup().roots[screen];
+    let home = env::temp_dir().join(format!("sampic-capture-{}", std::process::id()));
+    fs::remove_dir_all(&home).ok();
+    let saved: PathBuf = home.join("screenshots");
+    fs::create_dir_all(&saved).unwrap();
+    sampic(
+        &home,
+        &["config", "set", "local_path", saved.to_str().unwrap()],
+    );
+
+    let region = sampic(&home, &["local", "--region", "10,20,300,200"]);
+    assert!(Path::new(&region).starts_with(&saved));
+    assert_eq!(size(&region), (300, 200));
+
+    let last = sampic(&home, &["local", "--last-region"]);
+    assert_eq!(size(&last), (300, 200));
+    // The screen hasn't changed, so the same pixels get the same name.
+    assert_eq!(last, region);
+
+    let fullscreen = sampic(&home, &["local", "--fullscreen"]);
+    assert_eq!(
+        size(&fullscreen),
+        (
+            u32::from(screen.width_in_pixels),
+            u32::from(screen.height_in_pixels)
+        )
+    );
+    fs::remove_dir_all(&home).ok();
+}
@@ -0,0 +1,416 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::Client;
+use sampicore::keys::KeyStore;
+use sampicore::limits::{Client as Charged, Limiter, Limits};
+use sampicore::record::{self, Frame};
+use sampicore::server::{self, Store, Uploaded};
+use sampicore::storage::Local;
+use sampicore::uploads::Uploads;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Directory of a test's own for its keys, counters and uploads, removed
+/// when the test ends.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("sampic-test-{}-{}", std::process::id(), name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        Scratch(dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+fn launch(scratch: &Scratch, keys: KeyStore, limiter: Limiter) -> Client {
+    let path = scratch.path("uploads");
+    fs::create_dir_all(&path).unwrap();
+    let store = Store::new(
+        Box::new(Local::at(path)),
+        Some("https://sampic.test".into()),
+        Uploads::at(None).unwrap(),
+    );
+    Client::new(server::rocket(keys, limiter, store)).expect("valid rocket instance")
+}
+
+fn key_store(scratch: &Scratch) -> (KeyStore, String) {
+    let keys = KeyStore::at(scratch.path("keys.toml"));
+    let token = keys.add("tester".into()).unwrap();
+    (keys, token)
+}
+
+/// Half a minute into a minute, so tests counting requests per minute don't
+/// depend on when they run.
+fn frozen() -> u64 {
+    1_600_000_000 - 1_600_000_000 % 60 + 30
+}
+
+fn bearer(token: &str) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", token))
+}
+
+#[test]
+fn upload_without_token_is_unauthorized() {
+    let scratch = Scratch::new("unauthorized");
+    let (keys, _) = key_store(&scratch);
+    let limiter = Limiter::at(Limits::default(), Limits::default(), None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let response = client
+        .post("/upload?extension=png&w=1&h=1")
+        .body(vec![0u8; 4])
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+    // An empty token is no token, rather than a wrong one.
+    let response = client
+        .post("/upload?extension=png&w=1&h=1")
+        .header(bearer(""))
+        .body(vec![0u8; 4])
+        .dispatch();
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[test]
+fn uploads_are_served_back() {
+    let scratch = Scratch::new("roundtrip");
+    let (keys, token) = key_store(&scratch);
+    let limiter = Limiter::at(Limits::default(), Limits::default(), None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let mut response = client
+        .post("/upload?extension=png&w=2&h=2")
+        .header(bearer(&token))
+        .body(vec![255u8; 2 * 2 * 4])
+        .dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    let uploaded: Uploaded = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+    assert_eq!((uploaded.width, uploaded.height), (2, 2));
+    assert_eq!(uploaded.content_type, "image/png");
+    assert!(uploaded.delete_token.is_some());
+
+    let mut response = client.get(format!("/{}", uploaded.name)).dispatch();
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::PNG));
+    assert!(response.headers().get_one("ETag").is_some());
+    assert_eq!(
+        response.headers().get_one("Cache-Control"),
+        Some("public, max-age=31536000, immutable")
+    );
+    let stored = fs::read(scratch.path("uploads").join(&uploaded.name)).unwrap();
+    assert_eq!(response.body_bytes().unwrap(), stored);
+
+    let response = client.get("/missing.png").dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn uploads_are_deleted_with_their_token() {
+    let scratch = Scratch::new("delete");
+    let (keys, token) = key_store(&scratch);
+    let limiter = Limiter::at(Limits::default(), Limits::default(), None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let mut response = client
+        .post("/upload?extension=png&w=1&h=3")
+        .header(bearer(&token))
+        .body(vec![7u8; 3 * 4])
+        .dispatch();
+    let uploaded: Uploaded = serde_json::from_str(&response.body_string().unwrap()).unwrap();
+
+    let response = client.delete(format!("/{}", uploaded.name)).dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    let response = client
+        .delete(format!("/{}", uploaded.name))
+        .header(Header::new("X-Delete-Token", "guessed"))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+    // Tokens aren't taken from the URL, where they'd end up in logs.
+    let response = client
+        .delete(format!(
+            "/{}?token={}",
+            uploaded.name,
+            uploaded.delete_token.as_ref().unwrap()
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = client
+        .delete(format!("/{}", uploaded.name))
+        .header(Header::new(
+            "X-Delete-Token",
+            uploaded.delete_token.unwrap(),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    let response = client.get(format!("/{}", uploaded.name)).dispatch();
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn reuploads_keep_the_first_uploader() {
+    let scratch = Scratch::new("reupload");
+    let (keys, first) = key_store(&scratch);
+    let second = keys.add("someone else".into()).unwrap();
+    let per_key = Limits {
+        stored_bytes: 1000,
+        ..Limits::default()
+    };
+    let limiter = Limiter::at(Limits::default(), per_key, None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let upload = |token: &str| -> Uploaded {
+        let mut response = client
+            .post("/upload?extension=png&w=4&h=4")
+            .header(bearer(token))
+            .body(vec![9u8; 4 * 4 * 4])
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.body_string().unwrap()).unwrap()
+    };
+    let original = upload(&first);
+    let again = upload(&second);
+    assert_eq!(again.name, original.name);
+    assert_eq!(again.url, original.url);
+    assert!(again.delete_token.is_none());
+    // The second upload wasn't charged, so the first uploader's quota is
+    // all that's left to free.
+    let limiter = client.rocket().state::<Limiter>().unwrap();
+    assert_eq!(limiter.stored(&Charged::Key("someone else")), 0);
+    assert_eq!(limiter.stored(&Charged::Key("tester")), original.size);
+
+    let response = client
+        .delete(format!("/{}", original.name))
+        .header(Header::new(
+            "X-Delete-Token",
+            original.delete_token.unwrap(),
+        ))
+        .dispatch();
+    assert_eq!(response.status(), Status::NoContent);
+    assert_eq!(limiter.stored(&Charged::Key("tester")), 0);
+}
+
+#[test]
+fn encoded_uploads_must_be_valid_images() {
+    let scratch = Scratch::new("encoded");
+    let (keys, token) = key_store(&scratch);
+    let limiter = Limiter::at(Limits::default(), Limits::default(), None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let response = client
+        .post("/upload?v=2&extension=png")
+        .header(bearer(&token))
+        .body("definitely not a png")
+        .dispatch();
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+/// A two frame animation in `format`, one of `record::FORMATS`.
+fn animation(format: &str) -> Vec<u8> {
+    let frames: Vec<Frame> = [[255, 0, 0, 255], [0, 0, 255, 255]]
+        .iter()
+        .map(|color| Frame {
+            image: image::RgbaImage::from_pixel(6, 3, image::Rgba(*color)),
+            delay: std::time::Duration::from_millis(100),
+        })
+        .collect();
+    record::encode(&frames, format).unwrap().0
+}
+
+#[test]
+fn animations_are_decoded_before_they_are_accepted() {
+    let scratch = Scratch::new("animations");
+    let (keys, token) = key_store(&scratch);
+    let limiter = Limiter::at(Limits::default(), Limits::default(), None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let post = |extension: &str, body: Vec<u8>| -> Status {
+        client
+            .post(format!("/upload?v=2&extension={}", extension))
+            .header(bearer(&token))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+    for (format, extension) in [("gif", "gif"), ("apng", "png"), ("webp", "webp")].iter() {
+        let data = animation(format);
+        assert_eq!(post(extension, data.clone()), Status::Ok, "{}", format);
+
+        // A valid start followed by something that isn't an image.
+        let mut smuggled = data[..data.len() / 2].to_vec();
+        smuggled.extend_from_slice(b"#!/bin/sh\necho not an image\n");
+        assert_eq!(post(extension, smuggled), Status::BadRequest, "{}", format);
+    }
+
+    // Only the header and animation control chunk of an APNG.
+    let apng = animation("apng");
+    let actl = apng
+        .windows(4)
+        .position(|window| window == b"acTL")
+        .unwrap();
+    let mut header = apng[..actl + 4 + 8 + 4].to_vec();
+    header.extend_from_slice(&[0u8; 64]);
+    assert_eq!(post("png", header), Status::BadRequest);
+
+    // Anything appended to a WebP.
+    let mut webp = animation("webp");
+    webp.extend_from_slice(b"payload");
+    assert_eq!(post("webp", webp), Status::BadRequest);
+}
+
+#[test]
+fn daily_quota_is_enforced_per_key() {
+    let scratch = Scratch::new("daily");
+    let (keys, token) = key_store(&scratch);
+    let per_key = Limits {
+        bytes_per_day: 10,
+        ..Limits::default()
+    };
+    let limiter = Limiter::at(Limits::default(), per_key, None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let mut response = client
+        .post("/upload?extension=png&w=5&h=5")
+        .header(bearer(&token))
+        .body(vec![0u8; 100])
+        .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let retry_after: u64 = response
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0 && retry_after <= 24 * 60 * 60);
+    assert!(response
+        .body_string()
+        .unwrap()
+        .contains("Daily upload quota"));
+}
+
+#[test]
+fn rejected_uploads_count_against_the_daily_quota() {
+    let scratch = Scratch::new("rejected");
+    let (keys, token) = key_store(&scratch);
+    let per_key = Limits {
+        bytes_per_day: 12,
+        ..Limits::default()
+    };
+    let limiter = Limiter::at(Limits::default(), per_key, None).unwrap();
+    let client = launch(&scratch, keys, limiter);
+    let post = |url: &str, body: Vec<u8>| {
+        client
+            .post(url)
+            .header(bearer(&token))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+    assert_eq!(
+        post("/upload?v=2&extension=png", b"not a png".to_vec()),
+        Status::BadRequest
+    );
+    // 9 bytes were already received, only 3 are left.
+    assert_eq!(
+        post("/upload?extension=png&w=1&h=1", vec![0u8; 4]),
+        Status::TooManyRequests
+    );
+}
+
+#[test]
+fn x_real_ip_is_only_trusted_behind_a_proxy() {
+    let per_ip = Limits {
+        requests_per_minute: 1,
+        ..Limits::default()
+    };
+    let remote: SocketAddr = "10.0.0.2:4000".parse().unwrap();
+    for &trusted in [false, true].iter() {
+        let scratch = Scratch::new(&format!("proxy-{}", trusted));
+        let (keys, token) = key_store(&scratch);
+        let limiter = Limiter::at(per_ip, Limits::default(), None)
+            .unwrap()
+            .with_clock(frozen)
+            .trust_proxy(trusted);
+        let client = launch(&scratch, keys, limiter);
+        let statuses: Vec<Status> = ["192.0.2.1", "192.0.2.2"]
+            .iter()
+            .map(|ip| {
+                client
+                    .post("/upload?extension=png&w=1&h=1")
+                    .header(bearer(&token))
+                    .header(Header::new("X-Real-IP", *ip))
+                    .remote(remote)
+                    .body(vec![1u8; 4])
+                    .dispatch()
+                    .status()
+            })
+            .collect();
+        // Spoofed addresses don't get a fresh request allowance each.
+        let second = if trusted {
+            Status::Ok
+        } else {
+            Status::TooManyRequests
+        };
+        assert_eq!(statuses, vec![Status::Ok, second], "trusted: {}", trusted);
+    }
+}
+
+#[test]
+fn request_counters_survive_a_restart() {
+    let scratch = Scratch::new("restart");
+    let usage = scratch.path("usage.toml");
+    let (keys, token) = key_store(&scratch);
+    let per_ip = Limits {
+        requests_per_minute: 1,
+        bytes_per_day: 1,
+        ..Limits::default()
+    };
+    let remote: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+    let limiter = Limiter::at(per_ip, Limits::default(), Some(usage.clone()))
+        .unwrap()
+        .with_clock(frozen);
+    let client = launch(&scratch, keys, limiter);
+    // Passes the request limit but is turned down by the byte quota, so
+    // nothing ever reaches the storage backend.
+    let mut response = client
+        .post("/upload?extension=png&w=5&h=5")
+        .header(bearer(&token))
+        .remote(remote)
+        .body(vec![0u8; 100])
+        .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    assert!(response
+        .body_string()
+        .unwrap()
+        .contains("Daily upload quota"));
+    drop(response);
+    drop(client);
+
+    let keys = KeyStore::at(scratch.path("keys.toml"));
+    let limiter = Limiter::at(per_ip, Limits::default(), Some(usage))
+        .unwrap()
+        .with_clock(frozen);
+    let client = launch(&scratch, keys, limiter);
+    let mut response = client
+        .post("/upload?extension=png&w=5&h=5")
+        .header(bearer(&token))
+        .remote(remote)
+        .body(vec![0u8; 100])
+        .dispatch();
+    assert_eq!(response.status(), Status::TooManyRequests);
+    let retry_after: u64 = response
+        .headers()
+        .get_one("Retry-After")
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(retry_after, 30);
+    assert!(response
+        .body_string()
+        .unwrap()
+        .contains("Too many requests"));
+}
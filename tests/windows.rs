@@ -0,0 +1,70 @@
+//! Needs an X server without other windows in the way, e.g.
+//! `xvfb-run cargo test --test windows -- --ignored`.
+#![cfg(target_os = "linux")]
+use sampicore::windows;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, CreateWindowAux, PropMode, WindowClass};
+use x11rb::wrapper::ConnectionExt as _;
+
+#[test]
+#[ignore]
+fn windows_are_found_by_name_and_id() {
+    let (conn, screen) = x11rb::connect(None).expect("an X server, try xvfb-run");
+    let screen = &conn.setup().roots[screen];
+    let window = conn.generate_id().unwrap();
+    conn.create_window(
+        screen.root_depth,
+        window,
+        screen.root,
+        40,
+        30,
+        200,
+        100,
+        0,
+        WindowClass::INPUT_OUTPUT,
+        screen.root_visual,
+        &CreateWindowAux::new(),
+    )
+    .unwrap();
+    conn.change_property8(
+        PropMode::REPLACE,
+        window,
+        AtomEnum::WM_NAME,
+        AtomEnum::STRING,
+        b"sampic test window",
+    )
+    .unwrap();
+    conn.map_window(window).unwrap();
+    conn.sync().unwrap();
+
+    let found = windows::find("test window").unwrap();
+    assert_eq!(found.id, window);
+    assert_eq!(found.name, "sampic test window");
+    assert_eq!(found.rect, [40.0, 30.0, 200.0, 100.0]);
+
+    let found = windows::find(&format!("{:#x}", window)).unwrap();
+    assert_eq!(found.id, window);
+    let listed = windows::list().unwrap();
+    assert_eq!(windows::at(&listed, [100.0, 50.0]).unwrap().id, window);
+    assert!(windows::at(&listed, [5.0, 5.0]).is_none());
+}
+
+#[test]
+#[ignore]
+fn monitors_are_listed_with_their_names() {
+    let (conn, screen) = x11rb::connect(None).expect("an X server, try xvfb-run");
+    let screen = &conn.setup().roots[screen];
+    let monitors = windows::monitors().unwrap();
+    let first = &monitors[0];
+    assert_eq!(
+        first.rect,
+        [
+            0.0,
+            0.0,
+            f64::from(screen.width_in_pixels),
+            f64::from(screen.height_in_pixels)
+        ]
+    );
+    assert_eq!(windows::monitor_index(&first.name).unwrap(), 0);
+    assert!(windows::monitor_index("no such monitor").is_err());
+}